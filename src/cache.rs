@@ -0,0 +1,243 @@
+use std::{
+    any::{Any, TypeId},
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use crate::DataType;
+
+/// how many bytes of evaluated ranges a Dirfile keeps between calls
+pub const DEFAULT_FIELD_CACHE_BYTES: usize = 64 * 1024 * 1024;
+
+//first frame, first sample, number of samples and the type they were read as
+type RangeKey = (usize, usize, usize, TypeId);
+//the ranges read of one field
+type FieldRanges = HashMap<RangeKey, CachedRange>;
+
+struct CachedRange {
+    data: Arc<dyn Any + Send + Sync>, //a Vec of the type in its key
+    bytes: usize,
+    used: u64, //when it was last read or inserted, for eviction
+}
+
+#[derive(Default)]
+struct Ranges {
+    fields: HashMap<String, FieldRanges>,
+    bytes: usize,
+    clock: u64,
+    generation: u64, //bumped by clear
+}
+
+/// Evaluated ranges of fields, so an input shared by several derived fields is read once. When
+/// it holds more than its limit the least recently used ranges are dropped
+pub struct FieldCache {
+    ranges: Mutex<Ranges>,
+    limit: usize,                  //in bytes
+    keep: Option<HashSet<String>>, //the only fields kept, None for every field
+}
+
+impl FieldCache {
+    pub fn with_limit(limit: usize) -> FieldCache {
+        FieldCache {
+            ranges: Mutex::new(Ranges::default()),
+            limit,
+            keep: None,
+        }
+    }
+    /// a cache with no limit that only keeps the fields in `keep`, for one call
+    fn keeping(keep: HashSet<String>) -> FieldCache {
+        FieldCache {
+            keep: Some(keep),
+            ..FieldCache::with_limit(usize::MAX)
+        }
+    }
+    fn keeps(&self, name: &str) -> bool {
+        self.keep.as_ref().is_none_or(|keep| keep.contains(name))
+    }
+    /// copy a cached range into `out`, returning None if it has not been read yet
+    pub fn get<T>(
+        &self,
+        name: &str,
+        first_frame: usize,
+        first_sample: usize,
+        out: &mut [T],
+    ) -> Option<usize>
+    where
+        T: DataType,
+    {
+        if !self.keeps(name) {
+            return None;
+        }
+        let key = (first_frame, first_sample, out.len(), TypeId::of::<T>());
+        let mut ranges = self.ranges.lock().unwrap();
+        ranges.clock += 1;
+        let clock = ranges.clock;
+        let range = ranges.fields.get_mut(name)?.get_mut(&key)?;
+        range.used = clock;
+        let data = range.data.clone();
+        drop(ranges);
+        let data = data.downcast_ref::<Vec<T>>().unwrap();
+        out.copy_from_slice(data);
+        Some(data.len())
+    }
+    /// changes every time the cache is cleared. A read takes it before it starts and passes it
+    /// to insert, so a read that raced with a write does not leave its old data behind
+    pub fn generation(&self) -> u64 {
+        self.ranges.lock().unwrap().generation
+    }
+    /// remember a range that was read in full, unless the cache was cleared since `generation`
+    pub fn insert<T>(
+        &self,
+        generation: u64,
+        name: &str,
+        first_frame: usize,
+        first_sample: usize,
+        data: &[T],
+    ) where
+        T: DataType,
+    {
+        let bytes = std::mem::size_of_val(data);
+        if bytes > self.limit || !self.keeps(name) {
+            return;
+        }
+        let key = (first_frame, first_sample, data.len(), TypeId::of::<T>());
+        let mut ranges = self.ranges.lock().unwrap();
+        if ranges.generation != generation {
+            return;
+        }
+        ranges.clock += 1;
+        let range = CachedRange {
+            data: Arc::new(data.to_vec()),
+            bytes,
+            used: ranges.clock,
+        };
+        let replaced = ranges
+            .fields
+            .entry(name.to_string())
+            .or_default()
+            .insert(key, range);
+        ranges.bytes += bytes;
+        ranges.bytes -= replaced.map_or(0, |range| range.bytes);
+        while ranges.bytes > self.limit {
+            ranges.evict();
+        }
+    }
+    pub fn clear(&self) {
+        let mut ranges = self.ranges.lock().unwrap();
+        ranges.fields.clear();
+        ranges.bytes = 0;
+        ranges.generation += 1;
+    }
+}
+
+impl Ranges {
+    /// drop the least recently used range
+    fn evict(&mut self) {
+        let Some((name, key)) = self
+            .fields
+            .iter()
+            .flat_map(|(name, ranges)| ranges.iter().map(move |(key, range)| (name, key, range)))
+            .min_by_key(|(_, _, range)| range.used)
+            .map(|(name, key, _)| (name.clone(), *key))
+        else {
+            return;
+        };
+        let ranges = self.fields.get_mut(&name).unwrap();
+        self.bytes -= ranges.remove(&key).unwrap().bytes;
+        if ranges.is_empty() {
+            self.fields.remove(&name);
+        }
+    }
+}
+
+impl crate::Dirfile {
+    /// Keep evaluated inputs of derived fields between calls, up to `DEFAULT_FIELD_CACHE_BYTES`.
+    /// Writes through this Dirfile empty the cache, changes made to the files by anyone else
+    /// are not noticed
+    #[allow(dead_code)]
+    pub fn set_field_cache(&mut self, enabled: bool) {
+        self.field_cache = enabled.then(|| FieldCache::with_limit(DEFAULT_FIELD_CACHE_BYTES));
+    }
+    /// A cache for one call reading `names`, keeping only the inputs read more than once on the
+    /// way. None if nothing is read twice, so a plain read copies nothing into a cache
+    pub(crate) fn call_cache(&self, names: &[&str]) -> Option<FieldCache> {
+        let mut reads: HashMap<&str, usize> = HashMap::new();
+        //every requested field is evaluated, but a kept input is only evaluated the first time
+        let mut queue = names.to_vec();
+        while let Some(code) = queue.pop() {
+            let inputs = match self.entry(code) {
+                Some(entry) => entry.entry_type.inputs(),
+                //a representation reads the field it is a suffix of
+                None => crate::Representation::split(code)
+                    .map(|(field, _)| vec![field])
+                    .unwrap_or_default(),
+            };
+            for input in inputs {
+                let count = reads.entry(input).or_default();
+                *count += 1;
+                if *count == 1 {
+                    queue.push(input);
+                }
+            }
+        }
+        let keep: HashSet<String> = reads
+            .into_iter()
+            .filter(|&(_, count)| count > 1)
+            .map(|(input, _)| input.to_string())
+            .collect();
+        (!keep.is_empty()).then(|| FieldCache::keeping(keep))
+    }
+    /// forget everything in the field cache, for when the files were changed behind our back
+    pub fn clear_field_cache(&self) {
+        if let Some(field_cache) = &self.field_cache {
+            field_cache.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::tests::test_dirfile;
+
+    #[test]
+    fn call_cache() {
+        let dirfile = test_dirfile(
+            "call-cache",
+            "s RAW UINT16 1\nb0 BIT s 0\nb1 BIT s 1\nl LINCOM b0 1 0\nll LINCOM 2 l 1 0 s.r 1 0\n",
+        );
+        let keep = |names: &[&str]| {
+            let cache = dirfile.call_cache(names)?;
+            let mut keep: Vec<String> = cache.keep.unwrap().into_iter().collect();
+            keep.sort();
+            Some(keep)
+        };
+        //nothing is read twice
+        assert_eq!(keep(&["s"]), None);
+        assert_eq!(keep(&["l"]), None);
+        //dashboards reading many bits of one status word
+        assert_eq!(keep(&["b0", "b1"]).unwrap(), ["s"]);
+        //through a representation and down a chain of inputs
+        assert_eq!(keep(&["ll"]).unwrap(), ["s"]);
+        //a kept input is only evaluated once so its own inputs are not kept
+        assert_eq!(keep(&["l", "l"]).unwrap(), ["b0"]);
+        dirfile.putdata("s", 0, 0, &[1u16, 2, 3]).unwrap();
+        assert_eq!(
+            dirfile.getdata::<f64>("ll", 0, 0, 3, 0).unwrap(),
+            [2.0, 2.0, 4.0]
+        );
+        std::fs::remove_dir_all(&dirfile.root_dir).unwrap();
+    }
+
+    #[test]
+    fn kept_fields() {
+        let cache = super::FieldCache::keeping(HashSet::from(["s".to_string()]));
+        cache.insert(0, "s", 0, 0, &[1u16, 2]);
+        cache.insert(0, "t", 0, 0, &[3u16, 4]);
+        let mut out = [0u16; 2];
+        assert_eq!(cache.get("s", 0, 0, &mut out), Some(2));
+        assert_eq!(out, [1, 2]);
+        assert_eq!(cache.get("t", 0, 0, &mut out), None);
+    }
+}
@@ -0,0 +1,111 @@
+use std::{
+    collections::HashMap,
+    marker::PhantomData,
+    sync::{Condvar, Mutex},
+    thread::ThreadId,
+};
+
+/// The lock between reads and writes of RAW data. It works like RwLock except that a thread
+/// that already reads can read again while a writer waits. A RwLock may queue that second read
+/// behind the writer, which is waiting for the first read, so a thread holding a RawView that
+/// called getdata would hang. Other new readers wait for the writer so it is not starved
+#[derive(Default)]
+pub struct DataLock {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    readers: HashMap<ThreadId, usize>, //how many reads each reading thread holds
+    writing: bool,
+    waiting_writers: usize,
+}
+
+/// Held while reading. The reads are counted per thread so the guard stays on its thread
+pub struct ReadGuard<'a> {
+    lock: &'a DataLock,
+    _not_send: PhantomData<*const ()>,
+}
+
+/// Held while writing
+pub struct WriteGuard<'a> {
+    lock: &'a DataLock,
+}
+
+impl DataLock {
+    pub fn read(&self) -> ReadGuard<'_> {
+        let thread = std::thread::current().id();
+        let mut state = self.state.lock().unwrap();
+        //a waiting writer is waiting for this thread, so it goes ahead
+        if !state.readers.contains_key(&thread) {
+            while state.writing || state.waiting_writers > 0 {
+                state = self.changed.wait(state).unwrap();
+            }
+        }
+        *state.readers.entry(thread).or_default() += 1;
+        ReadGuard {
+            lock: self,
+            _not_send: PhantomData,
+        }
+    }
+    /// wait until nobody reads or writes. Reading on this thread while writing, or writing
+    /// while reading, never finishes
+    pub fn write(&self) -> WriteGuard<'_> {
+        let mut state = self.state.lock().unwrap();
+        state.waiting_writers += 1;
+        while state.writing || !state.readers.is_empty() {
+            state = self.changed.wait(state).unwrap();
+        }
+        state.waiting_writers -= 1;
+        state.writing = true;
+        WriteGuard { lock: self }
+    }
+}
+
+impl Drop for ReadGuard<'_> {
+    fn drop(&mut self) {
+        let thread = std::thread::current().id();
+        let mut state = self.lock.state.lock().unwrap();
+        let reads = state.readers.get_mut(&thread).unwrap();
+        *reads -= 1;
+        if *reads == 0 {
+            state.readers.remove(&thread);
+            self.lock.changed.notify_all();
+        }
+    }
+}
+
+impl Drop for WriteGuard<'_> {
+    fn drop(&mut self) {
+        self.lock.state.lock().unwrap().writing = false;
+        self.lock.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_again_while_a_writer_waits() {
+        let lock = DataLock::default();
+        let first = lock.read();
+        std::thread::scope(|scope| {
+            let writer = scope.spawn(|| {
+                let _writing = lock.write();
+                assert!(lock.state.lock().unwrap().readers.is_empty());
+            });
+            while lock.state.lock().unwrap().waiting_writers == 0 {
+                std::thread::yield_now();
+            }
+            //a std RwLock may block here
+            let second = lock.read();
+            drop(first);
+            assert!(!writer.is_finished());
+            drop(second);
+            writer.join().unwrap();
+        });
+        assert!(!lock.state.lock().unwrap().writing);
+    }
+}
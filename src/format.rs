@@ -1,16 +1,25 @@
 use nom::{
     branch::alt,
-    bytes::complete::{tag, take_while, take_while1},
-    character::complete::{multispace0, not_line_ending, space1},
-    combinator::{map, opt},
+    bytes::complete::{escaped_transform, tag, take_while1},
+    character::complete::{multispace0, none_of, not_line_ending, one_of, space1},
+    combinator::{map, opt, value},
     multi::many0,
-    sequence::{preceded, terminated, tuple},
+    sequence::preceded,
     IResult,
 };
-use std::{collections::HashMap, error::Error};
+use std::error::Error;
 type Result<T> = std::result::Result<T, Box<dyn Error>>;
 
-#[derive(Debug)]
+/// the newest Standards Version we understand
+pub const STANDARDS_VERSION: u32 = 10;
+
+// How the grammar changed between Standards Versions, as far as we care:
+//  * up to 5 directives may be written without the slash, so their names are reserved words
+//  * from 6 the slash is required and the directive names are ordinary field names
+//  * before 5 RAW types were written with the one letter codes, which are still accepted
+//  * directives and field types only exist from the version that introduced them
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 // pub struct Directive {
 //     pub name: String,
 //     pub args: Vec<String>,
@@ -24,11 +33,16 @@ pub enum Directive {
     Reference,
     Alias,
     Include,
+    FrameOffset,
+    Hidden,
+    Meta,
+    Namespace,
 }
 
-impl From<&str> for Directive {
-    fn from(value: &str) -> Self {
-        match value {
+impl TryFrom<&str> for Directive {
+    type Error = Box<dyn Error>;
+    fn try_from(value: &str) -> Result<Self> {
+        let directive = match value {
             "VERSION" => Directive::Version,
             "ENDIAN" => Directive::Endian,
             "PROTECT" => Directive::Protect,
@@ -36,39 +50,251 @@ impl From<&str> for Directive {
             "REFERENCE" => Directive::Reference,
             "ALIAS" => Directive::Alias,
             "INCLUDE" => Directive::Include,
-            _ => panic!("Unknown directive {}", value),
+            "FRAMEOFFSET" => Directive::FrameOffset,
+            "HIDDEN" => Directive::Hidden,
+            "META" => Directive::Meta,
+            "NAMESPACE" => Directive::Namespace,
+            _ => return Err(format!("Unknown directive {}", value).into()),
+        };
+        Ok(directive)
+    }
+}
+
+impl Directive {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Directive::Version => "VERSION",
+            Directive::Endian => "ENDIAN",
+            Directive::Protect => "PROTECT",
+            Directive::Encoding => "ENCODING",
+            Directive::Reference => "REFERENCE",
+            Directive::Alias => "ALIAS",
+            Directive::Include => "INCLUDE",
+            Directive::FrameOffset => "FRAMEOFFSET",
+            Directive::Hidden => "HIDDEN",
+            Directive::Meta => "META",
+            Directive::Namespace => "NAMESPACE",
+        }
+    }
+    /// the Standards Version that introduced the directive
+    pub fn since(&self) -> u32 {
+        match self {
+            Directive::FrameOffset => 1,
+            Directive::Include => 3,
+            Directive::Version | Directive::Endian => 5,
+            Directive::Protect | Directive::Encoding | Directive::Reference | Directive::Meta => 6,
+            Directive::Alias | Directive::Hidden => 9,
+            Directive::Namespace => 10,
+        }
+    }
+}
+
+/// the Standards Version that introduced a field type, None for types we do not know
+pub fn field_type_since(field_type: &str) -> Option<u32> {
+    match field_type {
+        "RAW" | "LINCOM" | "LINTERP" | "BIT" => Some(1),
+        "CONST" | "STRING" => Some(6),
+        "CARRAY" => Some(8),
+        "SARRAY" | "INDIR" | "SINDIR" => Some(10),
+        _ => None,
+    }
+}
+
+const LEGACY_TYPE_CODES: [(&str, &str); 8] = [
+    ("c", "UINT8"),
+    ("u", "UINT16"),
+    ("s", "INT16"),
+    ("U", "UINT32"),
+    ("S", "INT32"),
+    ("i", "INT32"),
+    ("f", "FLOAT32"),
+    ("d", "FLOAT64"),
+];
+
+/// the type name for a one letter RAW type code, anything else is returned as is
+pub fn expand_type_code(code: &str) -> &str {
+    LEGACY_TYPE_CODES
+        .iter()
+        .find(|(legacy, _)| *legacy == code)
+        .map_or(code, |(_, name)| name)
+}
+
+fn legacy_type_code(name: &str) -> Option<&'static str> {
+    LEGACY_TYPE_CODES
+        .iter()
+        .find(|(_, expanded)| *expanded == name)
+        .map(|(legacy, _)| *legacy)
+}
+
+/// the Standards Version given by a /VERSION line, if there is one
+pub fn find_version(input: &str) -> Option<u32> {
+    input.lines().find_map(|line| {
+        let mut tokens = line.split_whitespace();
+        match tokens.next() {
+            Some("/VERSION") | Some("VERSION") => tokens.next()?.parse().ok(),
+            _ => None,
+        }
+    })
+}
+
+/// best guess at the Standards Version of a format file without a /VERSION. Files that use
+/// bare directive names predate the slash and are read as the first version that has all of
+/// them, anything else is read with the current grammar
+pub fn guess_version(input: &str) -> u32 {
+    let mut bare_since = None;
+    for line in input.lines() {
+        let Some(first) = line.split_whitespace().next() else {
+            continue;
+        };
+        if first.starts_with('/') {
+            return STANDARDS_VERSION;
+        }
+        //only directives from before the slash was required can be written bare
+        match Directive::try_from(first) {
+            Ok(directive) if directive.since() <= 5 => {
+                bare_since = bare_since.max(Some(directive.since()));
+            }
+            _ => {}
         }
     }
+    bare_since.unwrap_or(STANDARDS_VERSION)
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct FieldDefinition {
     pub name: String,
     pub field_type: String,
     pub args: Vec<String>,
 }
 
-#[derive(Debug)]
+impl FieldDefinition {
+    /// the argument at `index`, erroring if the definition is too short
+    pub fn arg(&self, index: usize) -> Result<&str> {
+        self.args.get(index).map(String::as_str).ok_or_else(|| {
+            format!(
+                "{} {} is missing argument {}",
+                self.field_type,
+                self.name,
+                index + 1
+            )
+            .into()
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum Line {
     Directive(Directive, Vec<String>),
     FieldDefinition(FieldDefinition),
 }
 
+impl Line {
+    /// the line as written in a format file of Standards Version `version`
+    pub fn to_format(&self, version: u32) -> Result<String> {
+        let mut tokens = Vec::new();
+        match self {
+            Line::Directive(directive, args) => {
+                if directive.since() > version {
+                    return Err(format!(
+                        "/{} needs Standards Version {}",
+                        directive.name(),
+                        directive.since()
+                    )
+                    .into());
+                }
+                //the slash is optional in 5 but bare names are only needed before it
+                let slash = if version >= 5 { "/" } else { "" };
+                tokens.push(format!("{}{}", slash, directive.name()));
+                tokens.extend(args.iter().map(|arg| quote(arg)));
+            }
+            Line::FieldDefinition(field_definition) => {
+                let field_type = field_definition.field_type.as_str();
+                match field_type_since(field_type) {
+                    Some(since) if since > version => {
+                        return Err(format!(
+                            "{} {} needs Standards Version {}",
+                            field_type, field_definition.name, since
+                        )
+                        .into());
+                    }
+                    _ => {}
+                }
+                if version <= 5 && Directive::try_from(field_definition.name.as_str()).is_ok() {
+                    return Err(format!(
+                        "{} is a reserved word in Standards Version {}",
+                        field_definition.name, version
+                    )
+                    .into());
+                }
+                let mut args = field_definition.args.clone();
+                if field_type == "RAW" && version < 5 {
+                    let name = expand_type_code(field_definition.arg(0)?);
+                    args[0] = legacy_type_code(name)
+                        .ok_or_else(|| {
+                            format!("{} has no type code before Standards Version 5", name)
+                        })?
+                        .to_string();
+                } else if matches!(field_type, "RAW" | "CONST" | "CARRAY") {
+                    args[0] = expand_type_code(field_definition.arg(0)?).to_string();
+                }
+                tokens.push(quote(&field_definition.name));
+                tokens.push(field_type.to_string());
+                tokens.extend(args.iter().map(|arg| quote(arg)));
+            }
+        }
+        Ok(tokens.join(" "))
+    }
+}
+
+/// quote a token that would not survive being split on whitespace
+fn quote(token: &str) -> String {
+    if !token.is_empty() && !token.contains(|c: char| c.is_whitespace() || c == '"' || c == '\\') {
+        return token.to_string();
+    }
+    format!("\"{}\"", token.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
 fn is_not_space(c: char) -> bool {
     !c.is_whitespace()
 }
 
-fn parse_directive(input: &str) -> IResult<&str, (Directive, Vec<String>)> {
-    let (input, _) = tag("/")(input)?;
-    let (input, directive_name) = take_while1(is_not_space)(input)?;
-    let (input, args) = many0(preceded(space1, take_while1(is_not_space)))(input)?;
-    Ok((
-        input,
-        (
-            Directive::from(directive_name),
-            args.into_iter().map(String::from).collect(),
-        ),
-    ))
+// a token is either a bare word or a double quoted string which may contain whitespace
+// and the escapes \" and \\
+fn parse_token(input: &str) -> IResult<&str, String> {
+    alt((parse_quoted, map(take_while1(is_not_space), String::from)))(input)
+}
+
+fn parse_quoted(input: &str) -> IResult<&str, String> {
+    let (input, _) = tag("\"")(input)?;
+    if let Some(input) = input.strip_prefix('"') {
+        return Ok((input, String::new()));
+    }
+    let (input, token) = escaped_transform(
+        none_of("\\\""),
+        '\\',
+        alt((value("\\", one_of("\\")), value("\"", one_of("\"")))),
+    )(input)?;
+    let (input, _) = tag("\"")(input)?;
+    Ok((input, token))
+}
+
+fn parse_directive(version: u32) -> impl FnMut(&str) -> IResult<&str, (Directive, Vec<String>)> {
+    move |input| {
+        let (input, slash) = opt(tag("/"))(input)?;
+        let (rest, directive_name) = take_while1(is_not_space)(input)?;
+        //without a slash the line is only a directive while the names are reserved words
+        let directive = match Directive::try_from(directive_name) {
+            Ok(directive) if slash.is_some() || version <= 5 => directive,
+            _ => {
+                return Err(nom::Err::Error(nom::error::Error::new(
+                    input,
+                    nom::error::ErrorKind::Tag,
+                )))
+            }
+        };
+        let (rest, args) = many0(preceded(space1, parse_token))(rest)?;
+        Ok((rest, (directive, args)))
+    }
 }
 fn parse_field_definition(input: &str) -> IResult<&str, FieldDefinition> {
     if input.starts_with("/") {
@@ -80,13 +306,13 @@ fn parse_field_definition(input: &str) -> IResult<&str, FieldDefinition> {
     let (input, name) = take_while1(is_not_space)(input)?;
     let (input, _) = space1(input)?;
     let (input, field_type) = take_while1(is_not_space)(input)?;
-    let (input, args) = many0(preceded(space1, take_while1(is_not_space)))(input)?;
+    let (input, args) = many0(preceded(space1, parse_token))(input)?;
     Ok((
         input,
         FieldDefinition {
             name: name.to_string(),
             field_type: field_type.to_string(),
-            args: args.into_iter().map(String::from).collect(),
+            args,
         },
     ))
 }
@@ -96,26 +322,107 @@ fn parse_comment(input: &str) -> IResult<&str, &str> {
     preceded(tag("#"), not_line_ending)(input)
 }
 
-fn parse_line(input: &str) -> IResult<&str, Line> {
-    if input == "" {
-        //base case
-        return Err(nom::Err::Error(nom::error::Error::new(
-            input,
-            nom::error::ErrorKind::Eof,
-        )));
+fn parse_line(version: u32) -> impl FnMut(&str) -> IResult<&str, Line> {
+    move |input| {
+        if input.is_empty() {
+            //base case
+            return Err(nom::Err::Error(nom::error::Error::new(
+                input,
+                nom::error::ErrorKind::Eof,
+            )));
+        }
+        let (input, _) = many0(parse_comment)(input)?;
+        let (input, _) = multispace0(input)?;
+        let (input, line) = alt((
+            map(parse_directive(version), |(x, y)| Line::Directive(x, y)),
+            map(parse_field_definition, Line::FieldDefinition),
+        ))(input)?;
+        let (input, _) = multispace0(input)?;
+
+        Ok((input, line))
     }
+}
+
+/// parse a format file with the grammar of Standards Version `version`. Anything left over
+/// could not be parsed
+pub fn parse_format_file(input: &str, version: u32) -> IResult<&str, Vec<Line>> {
+    let (input, lines) = many0(parse_line(version))(input)?;
+    //trailing comments
     let (input, _) = many0(parse_comment)(input)?;
     let (input, _) = multispace0(input)?;
-    let (input, line) = alt((
-        map(parse_field_definition, Line::FieldDefinition),
-        map(parse_directive, |(x, y)| Line::Directive(x, y)),
-    ))(input)?;
-    let (input, _) = multispace0(input)?;
-    
-    Ok((input, line))
+    Ok((input, lines))
 }
 
-pub fn parse_format_file(input: &str) -> IResult<&str, Vec<Line>> {
-    let (input, lines) = many0(parse_line)(input)?;
-    Ok((input, lines))
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_detection() {
+        assert_eq!(find_version("x RAW UINT8 1\n/VERSION 9\n"), Some(9));
+        assert_eq!(find_version("VERSION 3\n"), Some(3));
+        assert_eq!(find_version("x RAW UINT8 1\n"), None);
+        //bare directive names only exist before the slash was required
+        assert_eq!(guess_version("ENDIAN big\nx RAW c 1\n"), 5);
+        assert_eq!(guess_version("FRAMEOFFSET 2\nx RAW c 1\n"), 1);
+        assert_eq!(guess_version("INCLUDE sub\nFRAMEOFFSET 2\n"), 3);
+        assert_eq!(
+            guess_version("/ENDIAN big\nx RAW UINT8 1\n"),
+            STANDARDS_VERSION
+        );
+        assert_eq!(guess_version("x RAW UINT8 1\n"), STANDARDS_VERSION);
+    }
+
+    #[test]
+    fn legacy_grammar() {
+        let (rest, lines) = parse_format_file("ENDIAN big\nx RAW c 1\n", 4).unwrap();
+        assert!(rest.is_empty());
+        assert!(matches!(&lines[0], Line::Directive(Directive::Endian, args) if args == &["big"]));
+        let Line::FieldDefinition(x) = &lines[1] else {
+            panic!("x is not a field");
+        };
+        assert_eq!(expand_type_code(&x.args[0]), "UINT8");
+        //from version 6 a directive name without the slash is a field name
+        let (_, lines) = parse_format_file("ENDIAN RAW UINT8 1\n", 6).unwrap();
+        assert!(matches!(&lines[0], Line::FieldDefinition(field) if field.name == "ENDIAN"));
+    }
+
+    #[test]
+    fn legacy_dirfiles() {
+        let root_dir = crate::tests::test_dir(
+            "legacy",
+            &[
+                ("format", "ENDIAN little\nx RAW d 1\nINCLUDE sub\n"),
+                ("sub", "FRAMEOFFSET 1\ny RAW c 2\n"),
+            ],
+        );
+        let dirfile = crate::Dirfile::open(root_dir, crate::OpenMode::Exclusive).unwrap();
+        let versions: Vec<u32> = dirfile
+            .fragments
+            .iter()
+            .map(|fragment| fragment.dirfile_options.version)
+            .collect();
+        //the included fragment carries on with the version of the one including it
+        assert_eq!(versions, [5, 5]);
+        dirfile.putdata("x", 0, 0, &[1.5]).unwrap();
+        assert_eq!(dirfile.getdata::<f64>("x", 0, 0, 0, 1).unwrap(), [1.5]);
+        dirfile.putdata("y", 1, 0, &[7u8, 8]).unwrap();
+        assert_eq!(dirfile.getdata::<u8>("y", 1, 0, 1, 0).unwrap(), [7, 8]);
+        //the guessed version still has to have everything the file uses
+        let error = crate::tests::open_error("legacy-version", "FRAMEOFFSET 1\nc CONST UINT8 1\n");
+        assert!(
+            error.contains("CONST c needs Standards Version 6"),
+            "{}",
+            error
+        );
+        std::fs::remove_dir_all(&dirfile.root_dir).unwrap();
+    }
+
+    #[test]
+    fn type_codes() {
+        assert_eq!(expand_type_code("d"), "FLOAT64");
+        assert_eq!(expand_type_code("FLOAT64"), "FLOAT64");
+        assert_eq!(legacy_type_code("UINT16"), Some("u"));
+        assert_eq!(legacy_type_code("UINT64"), None);
+    }
 }
@@ -1,10 +1,78 @@
-use std::{
-    io::{Read, Seek},
-    ops::{Add, Mul},
-    path,
-};
+use std::{any::Any, cell::RefCell};
+
+use num::Complex;
+
+use crate::{cache::FieldCache, DataType};
+
+/// One read for getdata_many, with the same meaning as the arguments of getdata
+#[derive(Debug, Clone)]
+pub struct FieldRequest<'a> {
+    pub name: &'a str,
+    pub first_frame: usize,
+    pub first_sample: usize,
+    pub num_frames: usize,
+    pub num_samples: usize,
+}
+
+/// Samples of a RAW field borrowed from the memory mapped file, see getdata_view. Writes
+/// through the Dirfile wait until the view is dropped
+pub struct RawView<'a, T> {
+    mmap: std::sync::Arc<memmap2::Mmap>,
+    start: usize, //in bytes
+    len: usize,   //in samples
+    _reading: crate::datalock::ReadGuard<'a>,
+    _marker: std::marker::PhantomData<T>,
+}
+
+impl<T> std::ops::Deref for RawView<'_, T> {
+    type Target = [T];
+    fn deref(&self) -> &[T] {
+        if self.len == 0 {
+            return &[];
+        }
+        // SAFETY: getdata_view only builds a view when T has the size and layout of the samples
+        // on disk in native byte order. The range is inside the map and starts a whole number of
+        // samples into it, the map is page aligned so the pointer is aligned for T. The view
+        // holds the data lock for reading so nothing written through this Dirfile changes or
+        // truncates the file while it is borrowed, and getdata_view only maps files of opens
+        // whose lock keeps writers in other processes out
+        unsafe {
+            std::slice::from_raw_parts(self.mmap.as_ptr().add(self.start) as *const T, self.len)
+        }
+    }
+}
+
+/// the bytes of `length` samples starting at sample `offset`, cut short at the end of the file
+fn raw_range(mmap: &[u8], size: usize, offset: usize, length: usize) -> &[u8] {
+    let samples = mmap.len() / size;
+    let start = offset.min(samples) * size;
+    let end = (offset + length).min(samples) * size;
+    &mmap[start..end]
+}
+thread_local! {
+    //buffers for the inputs of derived fields, kept between calls so reads do not allocate
+    static SCRATCH: RefCell<Vec<Box<dyn Any>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// run `f` with a buffer of `len` samples borrowed from this thread's scratch pool
+fn with_scratch<T, R>(len: usize, f: impl FnOnce(&mut [T]) -> R) -> R
+where
+    T: DataType,
+{
+    let mut buf: Box<Vec<T>> = SCRATCH
+        .with(|scratch| {
+            let mut scratch = scratch.borrow_mut();
+            let index = scratch.iter().position(|buf| buf.is::<Vec<T>>())?;
+            scratch.swap_remove(index).downcast().ok()
+        })
+        .unwrap_or_default();
+    buf.clear();
+    buf.resize(len, T::from_u64(0));
+    let result = f(&mut buf);
+    SCRATCH.with(|scratch| scratch.borrow_mut().push(buf));
+    result
+}
 
-use num::cast::AsPrimitive;
 impl crate::Dirfile {
     pub fn getdata<T>(
         &self,
@@ -13,178 +81,645 @@ impl crate::Dirfile {
         first_sample: usize,
         num_frames: usize,
         num_samples: usize,
-    ) -> Vec<T>
+    ) -> crate::Result<Vec<T>>
     where
-        T: 'static + Copy + std::ops::Mul<Output = T> + Add<Output = T> + AsPrimitive<T>,
-        f64: AsPrimitive<T>,
-        u32: AsPrimitive<T>,
-        i32: AsPrimitive<T>,
-        u64: AsPrimitive<T>,
+        T: DataType,
     {
-        let entry = self.entries.get(name).unwrap();
-
-        //get the length of the file
+        //inputs shared by several derived fields are only read once per call
+        let call_cache = match self.field_cache {
+            Some(_) => None,
+            None => self.call_cache(&[name]),
+        };
+        let cache = self.field_cache.as_ref().or(call_cache.as_ref());
+        let _reading = self.data_lock.read();
+        self.getdata_cached(
+            name,
+            first_frame,
+            first_sample,
+            num_frames,
+            num_samples,
+            cache,
+        )
+    }
+    fn getdata_cached<T>(
+        &self,
+        name: &str,
+        first_frame: usize,
+        first_sample: usize,
+        num_frames: usize,
+        num_samples: usize,
+        cache: Option<&FieldCache>,
+    ) -> crate::Result<Vec<T>>
+    where
+        T: DataType,
+    {
+        let length = num_frames * self.spf(name)? as usize + num_samples;
+        let mut data = vec![T::from_u64(0); length];
+        let read = self.evaluate(name, first_frame, first_sample, &mut data, cache)?;
+        data.truncate(read);
+        Ok(data)
+    }
+    /// Read from the position of `name` set by seek, moving it on past what was read
+    #[allow(dead_code)]
+    pub fn getdata_here<T>(
+        &self,
+        name: &str,
+        num_frames: usize,
+        num_samples: usize,
+    ) -> crate::Result<Vec<T>>
+    where
+        T: DataType,
+    {
+        let data = self.getdata(name, 0, self.tell(name), num_frames, num_samples)?;
+        self.advance(name, data.len());
+        Ok(data)
+    }
+    /// Read `out.len()` samples of `name` into `out` and return how many were read, which is
+    /// fewer than asked for at the end of the data. Nothing is allocated once the scratch
+    /// buffers for derived fields have grown to size, unless the field cache is turned on
+    #[allow(dead_code)]
+    pub fn getdata_into<T>(
+        &self,
+        name: &str,
+        first_frame: usize,
+        first_sample: usize,
+        out: &mut [T],
+    ) -> crate::Result<usize>
+    where
+        T: DataType,
+    {
+        let _reading = self.data_lock.read();
+        self.evaluate(
+            name,
+            first_frame,
+            first_sample,
+            out,
+            self.field_cache.as_ref(),
+        )
+    }
+    pub(crate) fn evaluate<T>(
+        &self,
+        name: &str,
+        first_frame: usize,
+        first_sample: usize,
+        out: &mut [T],
+        cache: Option<&FieldCache>,
+    ) -> crate::Result<usize>
+    where
+        T: DataType,
+    {
+        let entry = match self.entry(name) {
+            Some(entry) => entry,
+            None => {
+                //not a field name so it should be a field code with a representation suffix
+                let (field, representation) = crate::Representation::split(name)
+                    .ok_or_else(|| format!("no field named {}", name))?;
+                return with_scratch::<Complex<f64>, _>(out.len(), |inner| {
+                    let read = self.input(field, first_frame, first_sample, inner, cache)?;
+                    for (out, &val) in out.iter_mut().zip(&inner[..read]) {
+                        *out = T::from_complex(representation.apply(val));
+                    }
+                    Ok(read)
+                });
+            }
+        };
 
         match &entry.entry_type {
-            crate::EntryType::Raw(raw) => self.getraw(
-                raw,
-                entry.dirfile_path.join(name),
-                entry.dirfile_options.endian,
-                first_frame,
-                first_sample,
-                num_frames,
-                num_samples,
-            ),
-            crate::EntryType::Bit(bit) => {
-                self.getbit(bit, first_frame, first_sample, num_frames, num_samples)
-            }
+            crate::EntryType::Raw(raw) => self.getraw(raw, entry, first_frame, first_sample, out),
+            crate::EntryType::Bit(bit) => self.getbit(bit, first_frame, first_sample, out, cache),
             crate::EntryType::Lincom(lincom) => {
-                self.getlincom(lincom, first_frame, first_sample, num_frames, num_samples)
+                self.getlincom(lincom, first_frame, first_sample, out, cache)
             }
             crate::EntryType::Linterp(linterp) => {
-                self.getlinterp(linterp, first_frame, first_sample, num_frames, num_samples)
+                self.getlinterp(linterp, first_frame, first_sample, out, cache)
+            }
+            crate::EntryType::Indir(indir) => {
+                self.getindir(indir, first_frame, first_sample, out, cache)
+            }
+            crate::EntryType::Sindir(_) => {
+                Err(format!("{} is a SINDIR field - use getdata_strings", name).into())
+            }
+            crate::EntryType::Const(_)
+            | crate::EntryType::String(_)
+            | crate::EntryType::Carray(_)
+            | crate::EntryType::Sarray(_) => {
+                Err(format!("{} is a scalar field and has no vector data", name).into())
             }
         }
     }
-    fn getraw<T>(
+    /// read an input of a derived field, from the cache if it has already been read
+    fn input<T>(
         &self,
-        entry_raw: &crate::EntryRaw,
-        path: std::path::PathBuf,
-        endian: crate::Endian,
+        name: &str,
+        first_frame: usize,
+        first_sample: usize,
+        out: &mut [T],
+        cache: Option<&FieldCache>,
+    ) -> crate::Result<usize>
+    where
+        T: DataType,
+    {
+        let Some(cache) = cache else {
+            return self.evaluate(name, first_frame, first_sample, out, None);
+        };
+        if let Some(read) = cache.get(name, first_frame, first_sample, out) {
+            return Ok(read);
+        }
+        let generation = cache.generation();
+        let read = self.evaluate(name, first_frame, first_sample, out, Some(cache))?;
+        //a short read can get longer as data is written so it is not kept
+        if read == out.len() {
+            cache.insert(generation, name, first_frame, first_sample, out);
+        }
+        Ok(read)
+    }
+    /// Read several fields at once, spread over as many threads as there are cores. The
+    /// results are in the same order as the requests. Inputs the fields have in common are only
+    /// read once. The first request that fails, in request order, gives the error. The threads
+    /// are started for each call rather than kept in the Dirfile: scoped threads can borrow the
+    /// Dirfile and the requests without reference counting, and starting one costs little next
+    /// to reading a field. A single request is read on the calling thread
+    #[allow(dead_code)]
+    pub fn getdata_many<T>(&self, requests: &[FieldRequest]) -> crate::Result<Vec<Vec<T>>>
+    where
+        T: DataType,
+    {
+        let call_cache = match self.field_cache {
+            Some(_) => None,
+            None => {
+                let names: Vec<&str> = requests.iter().map(|request| request.name).collect();
+                self.call_cache(&names)
+            }
+        };
+        let cache = self.field_cache.as_ref().or(call_cache.as_ref());
+        let threads = std::thread::available_parallelism()
+            .map_or(1, |threads| threads.get())
+            .min(requests.len());
+        //each thread takes the next request until there are none left
+        let next = std::sync::atomic::AtomicUsize::new(0);
+        //errors are not Send so they cross back as their message
+        let mut results: Vec<std::result::Result<Vec<T>, String>> = Vec::new();
+        results.resize_with(requests.len(), || Ok(Vec::new()));
+        //held for the whole call so every request sees the same data
+        let _reading = self.data_lock.read();
+        if threads <= 1 {
+            return requests
+                .iter()
+                .map(|request| {
+                    self.getdata_cached(
+                        request.name,
+                        request.first_frame,
+                        request.first_sample,
+                        request.num_frames,
+                        request.num_samples,
+                        cache,
+                    )
+                })
+                .collect();
+        }
+        std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..threads)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut done = Vec::new();
+                        loop {
+                            let index = next.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                            let Some(request) = requests.get(index) else {
+                                break;
+                            };
+                            let data = self
+                                .getdata_cached(
+                                    request.name,
+                                    request.first_frame,
+                                    request.first_sample,
+                                    request.num_frames,
+                                    request.num_samples,
+                                    cache,
+                                )
+                                .map_err(|e| e.to_string());
+                            done.push((index, data));
+                        }
+                        done
+                    })
+                })
+                .collect();
+            for worker in workers {
+                //a panic in a worker is passed on as if the read had been done here
+                let done = worker
+                    .join()
+                    .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+                for (index, data) in done {
+                    results[index] = data;
+                }
+            }
+        });
+        results
+            .into_iter()
+            .map(|data| data.map_err(Into::into))
+            .collect()
+    }
+    #[allow(dead_code)]
+    pub fn getdata_strings(
+        &self,
+        name: &str,
         first_frame: usize,
         first_sample: usize,
         num_frames: usize,
         num_samples: usize,
-    ) -> Vec<T>
-    where
-        T: 'static + Copy,
-        f64: AsPrimitive<T>,
-        u32: AsPrimitive<T>,
-        u64: AsPrimitive<T>,
-    {
-        fn read_data<T, F>(
-            reader: &mut (impl Read + Seek),
-            offset: usize,
-            length: usize,
-            buf_size: usize,
-            convert: F,
-        ) -> Vec<T>
-        where
-            F: Fn(&[u8]) -> T,
-        {
-            let mut data: Vec<T> = Vec::with_capacity(length / buf_size);
-            let mut buf = vec![0; buf_size];
-            reader
-                .seek(std::io::SeekFrom::Start((offset * buf_size) as u64))
-                .unwrap();
-            while let Ok(()) = reader.read_exact(&mut buf) {
-                let value = convert(&buf);
-                data.push(value);
-                if data.capacity() == 0 {
-                    break;
-                }
+    ) -> crate::Result<Vec<String>> {
+        let entry = self
+            .entry(name)
+            .ok_or_else(|| format!("no field named {}", name))?;
+        match &entry.entry_type {
+            crate::EntryType::Sindir(sindir) => {
+                let values = self.getsarray(&sindir.sarray_field)?;
+                let index = self.getdata::<i64>(
+                    &sindir.parent_field,
+                    first_frame,
+                    first_sample,
+                    num_frames,
+                    num_samples,
+                )?;
+                //out of range indices give an empty string
+                Ok(index
+                    .into_iter()
+                    .map(|i| {
+                        usize::try_from(i)
+                            .ok()
+                            .and_then(|i| values.get(i))
+                            .cloned()
+                            .unwrap_or_default()
+                    })
+                    .collect())
             }
-            data
+            _ => Err(format!("{} is not a string valued vector field", name).into()),
         }
-
-        let offset = first_sample + first_frame * entry_raw.spf as usize;
-        let length = num_frames * entry_raw.spf as usize + num_samples;
-        let file = std::fs::File::open(path).unwrap();
-
-        let mut reader = std::io::BufReader::new(file);
-        match entry_raw.data_type {
-            crate::RawTypes::Float64 => {
-                read_data(&mut reader, offset, length, 8, |buf| match endian {
-                    crate::Endian::Big => f64::from_be_bytes(buf.try_into().unwrap()).as_(),
-                    crate::Endian::Little => f64::from_le_bytes(buf.try_into().unwrap()).as_(),
-                })
+    }
+    #[allow(dead_code)]
+    pub fn getconstant<T>(&self, name: &str) -> crate::Result<T>
+    where
+        T: DataType,
+    {
+        let entry = self
+            .entry(name)
+            .ok_or_else(|| format!("no field named {}", name))?;
+        match &entry.entry_type {
+            crate::EntryType::Const(constant) => Ok(T::from_complex(constant.value)),
+            _ => Err(format!("{} is not a CONST", name).into()),
+        }
+    }
+    #[allow(dead_code)]
+    pub fn getstring(&self, name: &str) -> crate::Result<String> {
+        let entry = self
+            .entry(name)
+            .ok_or_else(|| format!("no field named {}", name))?;
+        match &entry.entry_type {
+            crate::EntryType::String(string) => Ok(string.value.clone()),
+            _ => Err(format!("{} is not a STRING", name).into()),
+        }
+    }
+    #[allow(dead_code)]
+    pub fn getcarray<T>(&self, name: &str) -> crate::Result<Vec<T>>
+    where
+        T: DataType,
+    {
+        let entry = self
+            .entry(name)
+            .ok_or_else(|| format!("no field named {}", name))?;
+        match &entry.entry_type {
+            crate::EntryType::Carray(carray) => {
+                Ok(carray.values.iter().map(|&x| T::from_f64(x)).collect())
             }
-            crate::RawTypes::Uint64 => {
-                read_data(&mut reader, offset, length, 8, |buf| match endian {
-                    crate::Endian::Big => u64::from_be_bytes(buf.try_into().unwrap()).as_(),
-                    crate::Endian::Little => u64::from_le_bytes(buf.try_into().unwrap()).as_(),
-                })
+            _ => Err(format!("{} is not a CARRAY", name).into()),
+        }
+    }
+    #[allow(dead_code)]
+    pub fn getsarray(&self, name: &str) -> crate::Result<Vec<String>> {
+        let entry = self
+            .entry(name)
+            .ok_or_else(|| format!("no field named {}", name))?;
+        match &entry.entry_type {
+            crate::EntryType::Sarray(sarray) => Ok(sarray.values.clone()),
+            _ => Err(format!("{} is not a SARRAY", name).into()),
+        }
+    }
+    fn getraw<T>(
+        &self,
+        entry_raw: &crate::EntryRaw,
+        entry: &crate::Entry,
+        first_frame: usize,
+        first_sample: usize,
+        out: &mut [T],
+    ) -> crate::Result<usize>
+    where
+        T: DataType,
+    {
+        let dirfile_options = self.fragments[entry.fragment_index].dirfile_options;
+        let endian = dirfile_options.endian;
+        let offset = first_sample + first_frame * entry_raw.spf as usize;
+        let length = out.len();
+        //the file starts at the frame offset, anything before that is empty
+        let frame_offset = dirfile_options.frame_offset as usize * entry_raw.spf as usize;
+        let padding = frame_offset.saturating_sub(offset).min(length);
+        out[..padding].fill(T::from_f64(f64::NAN));
+        let offset = offset.saturating_sub(frame_offset);
+        let length = length - padding;
+        if length == 0 {
+            return Ok(padding);
+        }
+        let data_type = &entry_raw.data_type;
+        let size = data_type.size();
+        let out = &mut out[padding..];
+        let decode = |out: &mut [T], bytes: &[u8]| {
+            for (out, buf) in out.iter_mut().zip(bytes.chunks_exact(size)) {
+                *out = data_type.decode_native(buf);
             }
-            crate::RawTypes::Uint32 => {
-                read_data(&mut reader, offset, length, 4, |buf| match endian {
-                    crate::Endian::Big => u32::from_be_bytes(buf.try_into().unwrap()).as_(),
-                    crate::Endian::Little => u32::from_le_bytes(buf.try_into().unwrap()).as_(),
-                })
-            } // _ => {
-              //     unimplemented!("data type {:?}", entry_raw.data_type)
-              // }
+        };
+        if self.mode == crate::OpenMode::ReadOnly {
+            //another process may shorten the file at any time, which faults a map, so read a copy
+            let file = self
+                .raw_files
+                .lock()
+                .unwrap()
+                .get(&entry.name, &entry.dirfile_path, false)
+                .and_then(|raw_file| raw_file.handle())?;
+            return with_scratch::<u8, _>(length * size, |bytes| -> crate::Result<usize> {
+                let read = crate::rawfile::read_at(&file, (offset * size) as u64, bytes)?;
+                let bytes = &mut bytes[..read - read % size];
+                data_type.swap(endian, bytes);
+                decode(out, bytes);
+                Ok(padding + bytes.len() / size)
+            });
         }
+        let mmap = self
+            .raw_files
+            .lock()
+            .unwrap()
+            .get(&entry.name, &entry.dirfile_path, false)
+            .and_then(|raw_file| raw_file.map())?;
+        let bytes = raw_range(&mmap, size, offset, length);
+        //swap the whole range in one go rather than sample by sample
+        if data_type.needs_swap(endian) {
+            with_scratch::<u8, _>(bytes.len(), |swapped| {
+                swapped.copy_from_slice(bytes);
+                data_type.swap(endian, swapped);
+                decode(out, swapped);
+            });
+        } else {
+            decode(out, bytes);
+        }
+        Ok(padding + bytes.len() / size)
     }
 
-    fn getbit<T>(
+    /// Borrow the samples of a RAW field straight from the file without copying them. This only
+    /// works when `T` is the type on disk, the data is in native byte order and the range does
+    /// not start before the frame offset, otherwise None is returned and getdata should be used.
+    /// ReadOnly opens always get None, as another process could shorten the file under the
+    /// view. Writes through this Dirfile block until the view is dropped, so drop it before
+    /// writing on the same thread. Reading on any thread meanwhile is fine
+    #[allow(dead_code)]
+    pub fn getdata_view<T>(
         &self,
-        entry_bit: &crate::EntryBit,
+        name: &str,
         first_frame: usize,
         first_sample: usize,
         num_frames: usize,
         num_samples: usize,
-    ) -> Vec<T>
+    ) -> Option<RawView<'_, T>>
     where
-        T: 'static + Copy,
-        f64: AsPrimitive<T>,
-        u32: AsPrimitive<T>,
-        u64: AsPrimitive<T>,
+        T: DataType,
     {
-        //get underlying data
-        let inner = self.getdata::<u64>(
-            &entry_bit.parent_field,
-            first_frame,
-            first_sample,
-            num_frames,
-            num_samples,
-        );
-        //create a bit mask using entry_bit.num_bits and .first_bit
-        //then apply this bitmask to resut
-        let mask = u64::max_value() >> (u64::BITS - entry_bit.num_bits) << entry_bit.start_bit;
-        let data: Vec<T> = inner.into_iter().map(|val| (val & mask).as_()).collect();
-        return data;
+        if self.mode == crate::OpenMode::ReadOnly {
+            return None;
+        }
+        let entry = self.entry(name)?;
+        let crate::EntryType::Raw(raw) = &entry.entry_type else {
+            return None;
+        };
+        let dirfile_options = self.fragments[entry.fragment_index].dirfile_options;
+        if raw.data_type != T::RAW_TYPE
+            || raw.data_type.needs_swap(dirfile_options.endian)
+            || std::mem::size_of::<T>() != raw.data_type.size()
+        {
+            return None;
+        }
+        let offset = (first_sample + first_frame * raw.spf as usize)
+            .checked_sub(dirfile_options.frame_offset as usize * raw.spf as usize)?;
+        let length = num_frames * raw.spf as usize + num_samples;
+        let reading = self.data_lock.read();
+        let mmap = self
+            .raw_files
+            .lock()
+            .unwrap()
+            .get(&entry.name, &entry.dirfile_path, false)
+            .and_then(|raw_file| raw_file.map())
+            .ok()?;
+        let bytes = raw_range(&mmap, raw.data_type.size(), offset, length);
+        let start = bytes.as_ptr() as usize - mmap.as_ptr() as usize;
+        let len = bytes.len() / raw.data_type.size();
+        Some(RawView {
+            mmap,
+            start,
+            len,
+            _reading: reading,
+            _marker: std::marker::PhantomData,
+        })
+    }
+
+    fn getbit<T>(
+        &self,
+        entry_bit: &crate::EntryBit,
+        first_frame: usize,
+        first_sample: usize,
+        out: &mut [T],
+        cache: Option<&FieldCache>,
+    ) -> crate::Result<usize>
+    where
+        T: DataType,
+    {
+        let mask = entry_bit.mask();
+        with_scratch::<u64, _>(out.len(), |inner| {
+            //get underlying data
+            let read = self.input(
+                &entry_bit.parent_field,
+                first_frame,
+                first_sample,
+                inner,
+                cache,
+            )?;
+            for (out, &val) in out.iter_mut().zip(&inner[..read]) {
+                *out = T::from_u64((val & mask) >> entry_bit.start_bit);
+            }
+            Ok(read)
+        })
     }
     fn getlincom<T>(
         &self,
         entry_lincom: &crate::EntryLincom,
         first_frame: usize,
         first_sample: usize,
-        num_frames: usize,
-        num_samples: usize,
-    ) -> Vec<T>
+        out: &mut [T],
+        cache: Option<&FieldCache>,
+    ) -> crate::Result<usize>
     where
-        T: 'static + Copy + std::ops::Mul<Output = T> + Add<Output = T> + AsPrimitive<T>,
-        f64: AsPrimitive<T>,
-        u32: AsPrimitive<T>,
-        i32: AsPrimitive<T>,
-        u64: AsPrimitive<T>,
+        T: DataType,
     {
-        let mut entry_lincom = entry_lincom;
-        let mut data: Vec<T> = Vec::new();
-        loop {
-            //get underlying data
-            let inner = self.getdata::<T>(
-                &entry_lincom.parent_field,
+        //evaluate in double precision, in complex if any coefficient is, and only then convert to
+        //the output type so integer outputs do not truncate the coefficients
+        if entry_lincom.is_complex() {
+            self.getlincom_as::<Complex<f64>, T>(
+                entry_lincom,
                 first_frame,
                 first_sample,
-                num_frames,
-                num_samples,
-            );
-            if data.len() == 0 {
-                data = vec![0.as_(); inner.len()]
+                out,
+                cache,
+            )
+        } else {
+            self.getlincom_as::<f64, T>(entry_lincom, first_frame, first_sample, out, cache)
+        }
+    }
+    /// evaluate a LINCOM as `U` and convert the result into `out`
+    fn getlincom_as<U, T>(
+        &self,
+        entry_lincom: &crate::EntryLincom,
+        first_frame: usize,
+        first_sample: usize,
+        out: &mut [T],
+        cache: Option<&FieldCache>,
+    ) -> crate::Result<usize>
+    where
+        U: DataType,
+        T: DataType,
+    {
+        with_scratch::<U, _>(out.len(), |sum| {
+            let read = self.sum_lincom(entry_lincom, first_frame, first_sample, sum, cache)?;
+            for (out, &val) in out.iter_mut().zip(&sum[..read]) {
+                *out = T::from_complex(val.to_complex());
             }
-            data = data
-                .into_iter()
-                .zip(inner.into_iter())
-                .map(|(d, val)| d + entry_lincom.m.as_() * val + entry_lincom.b.as_())
-                .collect();
-            if entry_lincom.next_term.is_none() {
-                return data;
+            Ok(read)
+        })
+    }
+    fn sum_lincom<T>(
+        &self,
+        entry_lincom: &crate::EntryLincom,
+        first_frame: usize,
+        first_sample: usize,
+        out: &mut [T],
+        cache: Option<&FieldCache>,
+    ) -> crate::Result<usize>
+    where
+        T: DataType,
+    {
+        //the lincom runs at the rate of its first input, others are resampled to match
+        let spf = self.spf(&entry_lincom.terms[0].parent_field)?;
+        let start = first_frame * spf as usize + first_sample;
+        //the first term is read straight into the output, the others are added on
+        let first = &entry_lincom.terms[0];
+        let mut read = self.input(&first.parent_field, first_frame, first_sample, out, cache)?;
+        let (m, b) = (T::from_complex(first.m), T::from_complex(first.b));
+        for val in &mut out[..read] {
+            *val = m * *val + b;
+        }
+        for term in &entry_lincom.terms[1..] {
+            //get underlying data
+            read = with_scratch::<T, _>(read, |inner| -> crate::Result<usize> {
+                let inner_read = if self.spf(&term.parent_field)? == spf {
+                    self.input(&term.parent_field, first_frame, first_sample, inner, cache)?
+                } else {
+                    self.getdata_resampled(&term.parent_field, spf, start, inner, cache)?
+                };
+                let m = T::from_complex(term.m);
+                let b = T::from_complex(term.b);
+                for (d, &val) in out.iter_mut().zip(&inner[..inner_read]) {
+                    *d = *d + m * val + b;
+                }
+                Ok(inner_read)
+            })?;
+        }
+        Ok(read)
+    }
+    /// fill `out` with `name` starting at `first_sample` as if it had `spf` samples per frame,
+    /// each output sample takes the input sample at or just before it
+    fn getdata_resampled<T>(
+        &self,
+        name: &str,
+        spf: u32,
+        first_sample: usize,
+        out: &mut [T],
+        cache: Option<&FieldCache>,
+    ) -> crate::Result<usize>
+    where
+        T: DataType,
+    {
+        if out.is_empty() {
+            return Ok(0);
+        }
+        let field_spf = self.spf(name)? as usize;
+        let spf = spf as usize;
+        let first = first_sample * field_spf / spf;
+        let last = (first_sample + out.len() - 1) * field_spf / spf;
+        with_scratch::<T, _>(last - first + 1, |inner| {
+            let read = self.input(name, 0, first, inner, cache)?;
+            let mut resampled = 0;
+            for (out, sample) in out.iter_mut().zip(first_sample..) {
+                match inner[..read].get(sample * field_spf / spf - first) {
+                    Some(&val) => *out = val,
+                    None => break,
+                }
+                resampled += 1;
+            }
+            Ok(resampled)
+        })
+    }
+    /// number of frames in the dirfile, taken from the size of the reference field
+    #[allow(dead_code)]
+    pub fn nframes(&self) -> usize {
+        let Some(reference) = &self.reference else {
+            return 0;
+        };
+        //validate checked it is a RAW field when the dirfile was opened
+        let Some(entry) = self.entry(reference) else {
+            return 0;
+        };
+        let crate::EntryType::Raw(raw) = &entry.entry_type else {
+            return 0;
+        };
+        //through the open file so appends that are still buffered count
+        let bytes = self
+            .raw_files
+            .lock()
+            .unwrap()
+            .get(&entry.name, &entry.dirfile_path, false)
+            .and_then(|raw_file| raw_file.len())
+            .map_or(0, |len| len as usize);
+        let frame_offset = self.fragments[entry.fragment_index]
+            .dirfile_options
+            .frame_offset as usize;
+        bytes / (raw.data_type.size() * raw.spf as usize) + frame_offset
+    }
+    /// samples per frame of a vector field, derived fields take the rate of their first input
+    pub fn spf(&self, name: &str) -> crate::Result<u32> {
+        let entry = match self.entry(name) {
+            Some(entry) => entry,
+            None => {
+                let (field, _) = crate::Representation::split(name)
+                    .ok_or_else(|| format!("no field named {}", name))?;
+                return self.spf(field);
+            }
+        };
+        match &entry.entry_type {
+            crate::EntryType::Raw(raw) => Ok(raw.spf),
+            crate::EntryType::Bit(bit) => self.spf(&bit.parent_field),
+            crate::EntryType::Lincom(lincom) => self.spf(&lincom.terms[0].parent_field),
+            crate::EntryType::Linterp(linterp) => self.spf(&linterp.parent_field),
+            crate::EntryType::Indir(indir) => self.spf(&indir.parent_field),
+            crate::EntryType::Sindir(sindir) => self.spf(&sindir.parent_field),
+            crate::EntryType::Const(_)
+            | crate::EntryType::String(_)
+            | crate::EntryType::Carray(_)
+            | crate::EntryType::Sarray(_) => {
+                Err(format!("{} is a scalar field and has no vector data", name).into())
             }
-            entry_lincom = entry_lincom
-                .next_term
-                .as_ref()
-                .expect("just checked its there");
         }
     }
     fn getlinterp<T>(
@@ -192,21 +727,18 @@ impl crate::Dirfile {
         entry_linterp: &crate::EntryLinterp,
         first_frame: usize,
         first_sample: usize,
-        num_frames: usize,
-        num_samples: usize,
-    ) -> Vec<T>
-    where
-    T: 'static + Copy + std::ops::Mul<Output = T> + Add<Output = T> + AsPrimitive<T>,
-        f64: AsPrimitive<T>,
-        u32: AsPrimitive<T>,
-        i32: AsPrimitive<T>,
-        u64: AsPrimitive<T>,
-    {
-        let linterp = |val: f64| -> f64 {
-            // do binary search to find val inside entry_linterp.x
-            // then use the index to find the corresponding value in entry_linterp.y
-            let x = &entry_linterp.x;
-            let y = &entry_linterp.y;
+        out: &mut [T],
+        cache: Option<&FieldCache>,
+    ) -> crate::Result<usize>
+    where
+        T: DataType,
+    {
+        let table = entry_linterp.table()?;
+        let linterp = |val: f64| -> Complex<f64> {
+            // do binary search to find val inside table.x
+            // then use the index to find the corresponding value in table.y
+            let x = &table.x;
+            let y = &table.y;
             let mut low = 0;
             let mut high = x.len();
             while low < high {
@@ -227,19 +759,316 @@ impl crate::Dirfile {
                 let x2 = x[low];
                 let y1 = y[low - 1];
                 let y2 = y[low];
-                y1 + (val - x1) * (y2 - y1) / (x2 - x1)
+                y1 + (y2 - y1) * ((val - x1) / (x2 - x1))
             }
         };
 
-        let inner = self.getdata::<f64>(
-            &entry_linterp.parent_field,
-            first_frame,
-            first_sample,
-            num_frames,
-            num_samples,
+        with_scratch::<f64, _>(out.len(), |inner| {
+            let read = self.input(
+                &entry_linterp.parent_field,
+                first_frame,
+                first_sample,
+                inner,
+                cache,
+            )?;
+            for (out, &val) in out.iter_mut().zip(&inner[..read]) {
+                *out = T::from_complex(linterp(val));
+            }
+            Ok(read)
+        })
+    }
+    fn getindir<T>(
+        &self,
+        entry_indir: &crate::EntryIndir,
+        first_frame: usize,
+        first_sample: usize,
+        out: &mut [T],
+        cache: Option<&FieldCache>,
+    ) -> crate::Result<usize>
+    where
+        T: DataType,
+    {
+        let entry = self
+            .entry(&entry_indir.carray_field)
+            .ok_or_else(|| format!("no field named {}", entry_indir.carray_field))?;
+        let crate::EntryType::Carray(carray) = &entry.entry_type else {
+            return Err(format!("{} is not a CARRAY", entry_indir.carray_field).into());
+        };
+        with_scratch::<i64, _>(out.len(), |index| {
+            let read = self.input(
+                &entry_indir.parent_field,
+                first_frame,
+                first_sample,
+                index,
+                cache,
+            )?;
+            //out of range indices give NaN (which is zero for integer types)
+            for (out, &i) in out.iter_mut().zip(&index[..read]) {
+                *out = T::from_f64(
+                    usize::try_from(i)
+                        .ok()
+                        .and_then(|i| carray.values.get(i))
+                        .copied()
+                        .unwrap_or(f64::NAN),
+                );
+            }
+            Ok(read)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use num::Complex;
+
+    use crate::tests::test_dirfile;
+
+    #[test]
+    fn indir_and_sindir() {
+        let dirfile = test_dirfile(
+            "indir",
+            "i RAW UINT8 1\nc CARRAY FLOAT64 1.5 2.5 3.5\nd INDIR i c\n\
+             s SARRAY a b c\nsd SINDIR i s\n",
+        );
+        dirfile.putdata("i", 0, 0, &[0u8, 2, 5]).unwrap();
+        //out of range indices are NaN or an empty string
+        let read = dirfile.getdata::<f64>("d", 0, 0, 0, 3).unwrap();
+        assert_eq!(read[..2], [1.5, 3.5]);
+        assert!(read[2].is_nan());
+        assert_eq!(
+            dirfile.getdata_strings("sd", 0, 0, 0, 3).unwrap(),
+            ["a", "c", ""]
         );
+        assert!(dirfile.getdata::<f64>("sd", 0, 0, 0, 3).is_err());
+        assert!(dirfile.getdata_strings("d", 0, 0, 0, 3).is_err());
+        let missing = dirfile.getdata_strings("nope", 0, 0, 0, 3).unwrap_err();
+        assert_eq!(missing.to_string(), "no field named nope");
+        assert_eq!(dirfile.getcarray::<i32>("c").unwrap(), [1, 2, 3]);
+        assert_eq!(dirfile.getsarray("s").unwrap(), ["a", "b", "c"]);
+        assert!(dirfile.getcarray::<f64>("s").is_err());
+        assert!(dirfile.getsarray("nope").is_err());
+        std::fs::remove_dir_all(&dirfile.root_dir).unwrap();
+    }
 
-        let result: Vec<T> = inner.into_iter().map(|val| linterp(val).as_()).collect();
-        return result;
+    #[test]
+    fn complex_parameters() {
+        let dirfile = test_dirfile(
+            "complex",
+            "x RAW FLOAT64 1
+l LINCOM x 1;1 0;-1
+p LINCOM x 2@0 0
+t LINTERP x table\n",
+        );
+        dirfile.putdata("x", 0, 0, &[2.0, 5.0]).unwrap();
+        assert_eq!(
+            dirfile.getdata::<Complex<f64>>("l", 0, 0, 0, 2).unwrap(),
+            [Complex::new(2.0, 1.0), Complex::new(5.0, 4.0)]
+        );
+        assert_eq!(
+            dirfile.getdata::<Complex<f32>>("l", 0, 0, 0, 1).unwrap(),
+            [Complex::new(2.0, 1.0)]
+        );
+        //real outputs take the real part
+        assert_eq!(dirfile.getdata::<f64>("l", 0, 0, 0, 2).unwrap(), [2.0, 5.0]);
+        assert_eq!(
+            dirfile.getdata::<f64>("p", 0, 0, 0, 2).unwrap(),
+            [4.0, 10.0]
+        );
+        //the table is only read when the field is
+        let missing = dirfile.getdata::<f64>("t", 0, 0, 0, 2).unwrap_err();
+        assert!(missing.to_string().contains("cannot read LINTERP table"));
+        std::fs::write(dirfile.root_dir.join("table"), "0 0;0\n10 10;20\n").unwrap();
+        assert_eq!(
+            dirfile.getdata::<Complex<f64>>("t", 0, 0, 0, 2).unwrap(),
+            [Complex::new(2.0, 4.0), Complex::new(5.0, 10.0)]
+        );
+        std::fs::remove_dir_all(&dirfile.root_dir).unwrap();
+    }
+
+    #[test]
+    fn representations() {
+        let dirfile = test_dirfile(
+            "representations",
+            "x RAW FLOAT64 1\nz LINCOM x 3;4 0\nm LINCOM z.m 2 0\n",
+        );
+        dirfile.putdata("x", 0, 0, &[1.0, 2.0]).unwrap();
+        let read = |code| dirfile.getdata::<f64>(code, 0, 0, 0, 2).unwrap();
+        assert_eq!(read("z.r"), [3.0, 6.0]);
+        assert_eq!(read("z.i"), [4.0, 8.0]);
+        assert_eq!(read("z.m"), [5.0, 10.0]);
+        assert_eq!(read("z.a"), [4f64.atan2(3.0); 2]);
+        assert_eq!(read("x.i"), [0.0, 0.0]);
+        assert_eq!(
+            dirfile.getdata::<Complex<f64>>("z.z", 0, 0, 0, 1).unwrap(),
+            [Complex::new(3.0, 4.0)]
+        );
+        //as the input of a derived field
+        assert_eq!(read("m"), [10.0, 20.0]);
+        assert!(dirfile.getdata::<f64>("z.q", 0, 0, 0, 1).is_err());
+        std::fs::remove_dir_all(&dirfile.root_dir).unwrap();
+    }
+
+    #[test]
+    fn lincom_evaluation() {
+        let dirfile = test_dirfile(
+            "lincom-evaluation",
+            "x RAW INT16 1\nh LINCOM x 0.5 0\nf RAW FLOAT64 2\ny RAW FLOAT64 1\n\
+             s LINCOM 2 f 1 0 y 1 0.5\n",
+        );
+        dirfile.putdata("x", 0, 0, &[10, 21]).unwrap();
+        //the coefficients are not cut to the output type
+        assert_eq!(dirfile.getdata::<i64>("h", 0, 0, 0, 2).unwrap(), [5, 10]);
+        assert_eq!(
+            dirfile.getdata::<f64>("h", 0, 0, 0, 2).unwrap(),
+            [5.0, 10.5]
+        );
+        //the second input has half the rate of the first and is resampled to it
+        dirfile.putdata("f", 0, 0, &[1.0, 2.0, 3.0, 4.0]).unwrap();
+        dirfile.putdata("y", 0, 0, &[10.0, 20.0]).unwrap();
+        assert_eq!(
+            dirfile.getdata::<f64>("s", 0, 0, 2, 0).unwrap(),
+            [11.5, 12.5, 23.5, 24.5]
+        );
+        assert_eq!(dirfile.getdata::<f64>("s", 1, 1, 0, 1).unwrap(), [24.5]);
+        std::fs::remove_dir_all(&dirfile.root_dir).unwrap();
+    }
+
+    #[test]
+    fn frame_offset() {
+        let dirfile = test_dirfile(
+            "nframes",
+            "/FRAMEOFFSET 2\nx RAW UINT16 2\ny RAW UINT16 1\n/REFERENCE y\n",
+        );
+        //the reference field counts from the frame offset
+        assert_eq!(dirfile.nframes(), 2);
+        dirfile.putdata("y", 2, 0, &[1, 2, 3]).unwrap();
+        assert_eq!(dirfile.nframes(), 5);
+        //frames before the offset are empty, which is zero for integers
+        dirfile.putdata("x", 3, 0, &[7, 8]).unwrap();
+        assert_eq!(
+            dirfile.getdata::<u16>("x", 1, 0, 3, 0).unwrap(),
+            [0, 0, 0, 0, 7, 8]
+        );
+        std::fs::remove_dir_all(&dirfile.root_dir).unwrap();
+    }
+
+    #[test]
+    fn views() {
+        let dirfile = test_dirfile("views", "x RAW UINT16 1\ny RAW UINT32 1\n");
+        dirfile.putdata("x", 0, 0, &[1u16, 2, 3]).unwrap();
+        assert_eq!(
+            *dirfile.getdata_view::<u16>("x", 0, 1, 0, 5).unwrap(),
+            [2, 3]
+        );
+        assert!(dirfile.getdata_view::<u32>("x", 0, 0, 0, 1).is_none());
+        //nothing keeps another process from shortening the files of a read only open
+        let reader = crate::Dirfile::open_readonly(dirfile.root_dir.clone()).unwrap();
+        assert!(reader.getdata_view::<u16>("x", 0, 0, 0, 3).is_none());
+        assert_eq!(reader.getdata::<u16>("x", 0, 0, 0, 3).unwrap(), [1, 2, 3]);
+        std::fs::remove_dir_all(&dirfile.root_dir).unwrap();
+    }
+
+    #[test]
+    fn read_only_truncation() {
+        let dirfile = test_dirfile("truncation", "x RAW UINT16 1\n");
+        dirfile.putdata("x", 0, 0, &[7u16; 100]).unwrap();
+        let reader = crate::Dirfile::open_readonly(dirfile.root_dir.clone()).unwrap();
+        assert_eq!(reader.getdata::<u16>("x", 0, 0, 0, 100).unwrap().len(), 100);
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(dirfile.root_dir.join("x"))
+            .unwrap();
+        file.set_len(11).unwrap();
+        //the half sample at the end is left out
+        assert_eq!(reader.getdata::<u16>("x", 0, 0, 0, 100).unwrap(), [7; 5]);
+        std::fs::remove_dir_all(&dirfile.root_dir).unwrap();
+    }
+
+    #[test]
+    fn read_while_writer_waits() {
+        let dirfile = test_dirfile("view-deadlock", "x RAW UINT16 1\n");
+        dirfile.putdata("x", 0, 0, &[1u16, 2]).unwrap();
+        let view = dirfile.getdata_view::<u16>("x", 0, 0, 0, 2).unwrap();
+        std::thread::scope(|scope| {
+            let writer = scope.spawn(|| dirfile.putdata("x", 0, 2, &[3u16]).unwrap());
+            //give the writer time to start waiting for the view
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            assert_eq!(dirfile.getdata::<u16>("x", 0, 0, 0, 3).unwrap(), [1, 2]);
+            assert_eq!(*view, [1, 2]);
+            drop(view);
+            writer.join().unwrap();
+        });
+        assert_eq!(dirfile.getdata::<u16>("x", 0, 0, 0, 3).unwrap(), [1, 2, 3]);
+        std::fs::remove_dir_all(&dirfile.root_dir).unwrap();
+    }
+
+    #[test]
+    fn reads_into_buffers() {
+        let dirfile = test_dirfile(
+            "into",
+            "x RAW UINT16 1\nl LINCOM x 2 1\nb BIT x 1 2\nm LINCOM 2 l 1 0 x.r 1 0\n",
+        );
+        dirfile.putdata("x", 0, 0, &[1u16, 2, 6]).unwrap();
+        let mut out = [0.0; 2];
+        //the same buffer is read into again and again, as a plotting loop would
+        for (frame, lincom, bit, sum) in [
+            (0, [3.0, 5.0], [0.0, 1.0], [4.0, 7.0]),
+            (1, [5.0, 13.0], [1.0, 3.0], [7.0, 19.0]),
+        ] {
+            assert_eq!(dirfile.getdata_into("l", frame, 0, &mut out).unwrap(), 2);
+            assert_eq!(out, lincom);
+            assert_eq!(dirfile.getdata_into("b", frame, 0, &mut out).unwrap(), 2);
+            assert_eq!(out, bit);
+            assert_eq!(dirfile.getdata_into("m", frame, 0, &mut out).unwrap(), 2);
+            assert_eq!(out, sum);
+        }
+        //a read past the end says how much of the buffer was filled
+        assert_eq!(dirfile.getdata_into("l", 2, 0, &mut out).unwrap(), 1);
+        assert_eq!(out[0], 13.0);
+        assert_eq!(dirfile.getdata_into("l", 3, 0, &mut out).unwrap(), 0);
+        assert!(dirfile.getdata_into("nothing", 0, 0, &mut out).is_err());
+        std::fs::remove_dir_all(&dirfile.root_dir).unwrap();
+    }
+
+    #[test]
+    fn many_fields() {
+        let dirfile = test_dirfile(
+            "many",
+            "x RAW UINT16 1\ny RAW FLOAT64 2\nl LINCOM x 2 0\nll LINCOM 2 l 1 0 x 1 0\n",
+        );
+        dirfile.putdata("x", 0, 0, &[1u16, 2, 3]).unwrap();
+        dirfile.putdata("y", 0, 0, &[0.5, 1.5]).unwrap();
+        let request = |name| super::FieldRequest {
+            name,
+            first_frame: 0,
+            first_sample: 0,
+            num_frames: 2,
+            num_samples: 0,
+        };
+        let names = ["ll", "y", "x", "l", "y", "x.m", "ll"];
+        let requests: Vec<_> = names.iter().map(|&name| request(name)).collect();
+        let many = dirfile.getdata_many::<f64>(&requests).unwrap();
+        for (name, data) in names.iter().zip(&many) {
+            assert_eq!(
+                data,
+                &dirfile.getdata::<f64>(name, 0, 0, 2, 0).unwrap(),
+                "{}",
+                name
+            );
+        }
+        assert_eq!(many[0], [3.0, 6.0]);
+        assert_eq!(many[1], [0.5, 1.5]);
+        assert!(dirfile.getdata_many::<f64>(&[]).unwrap().is_empty());
+        assert_eq!(
+            dirfile.getdata_many::<f64>(&[request("l")]).unwrap(),
+            [[2.0, 4.0]]
+        );
+        //the first request that fails gives the error
+        let error = dirfile
+            .getdata_many::<f64>(&[request("x"), request("a"), request("b")])
+            .unwrap_err();
+        assert_eq!(error.to_string(), "no field named a");
+        assert!(dirfile.getdata_many::<f64>(&[request("b")]).is_err());
+        std::fs::remove_dir_all(&dirfile.root_dir).unwrap();
     }
 }
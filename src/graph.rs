@@ -0,0 +1,230 @@
+use std::collections::HashSet;
+
+impl crate::Dirfile {
+    /// Check the field definitions without reading any data. Every input has to name a field
+    /// and no field may depend on itself, all the problems found are listed in the error
+    pub fn validate(&self) -> crate::Result<()> {
+        let mut problems = Vec::new();
+        let names = self.entry_names();
+        for &name in &names {
+            for input in self.entry(name).unwrap().entry_type.inputs() {
+                if self.resolve_input(input).is_none() {
+                    problems.push(format!("{} reads {} which is not defined", name, input));
+                }
+            }
+        }
+        //nframes counts the frames of the reference field
+        if let Some(reference) = &self.reference {
+            match self.entry(reference).map(|entry| &entry.entry_type) {
+                Some(crate::EntryType::Raw(_)) => {}
+                Some(entry_type) => problems.push(format!(
+                    "the reference field {} is a {} field, it has to be RAW",
+                    reference,
+                    entry_type.type_name()
+                )),
+                None => problems.push(format!("the reference field {} is not defined", reference)),
+            }
+        }
+        let mut done = HashSet::new();
+        for &name in &names {
+            self.find_cycles(name, &mut Vec::new(), &mut done, &mut problems);
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems.join("\n").into())
+        }
+    }
+
+    /// The fields `name` reads, sorted. With `transitive` their inputs are followed all the way
+    /// down to fields that read nothing, like RAW and CONST fields
+    #[allow(dead_code)]
+    pub fn inputs(&self, name: &str, transitive: bool) -> crate::Result<Vec<String>> {
+        let mut found = HashSet::new();
+        let mut queue = vec![name];
+        while let Some(field) = queue.pop() {
+            let entry = self
+                .entry(field)
+                .ok_or_else(|| format!("no field named {}", field))?;
+            //validate made sure every input is defined when the dirfile was opened
+            for input in entry
+                .entry_type
+                .inputs()
+                .into_iter()
+                .flat_map(|input| self.resolve_input(input))
+            {
+                if found.insert(input) && transitive {
+                    queue.push(input);
+                }
+            }
+        }
+        Ok(sorted(found))
+    }
+
+    /// The fields that read `name`, sorted. With `transitive` the fields that read those are
+    /// included too, which is everything a change to `name` affects
+    #[allow(dead_code)]
+    pub fn dependents(&self, name: &str, transitive: bool) -> crate::Result<Vec<String>> {
+        let entry = self
+            .entry(name)
+            .ok_or_else(|| format!("no field named {}", name))?;
+        let names = self.entry_names();
+        let mut found = HashSet::new();
+        //inputs are stored under the names the fields were defined with
+        let mut queue = vec![entry.name.as_str()];
+        while let Some(field) = queue.pop() {
+            for &dependent in &names {
+                let reads_field = self
+                    .entry(dependent)
+                    .unwrap()
+                    .entry_type
+                    .inputs()
+                    .into_iter()
+                    .any(|input| self.resolve_input(input) == Some(field));
+                if reads_field && found.insert(dependent) && transitive {
+                    queue.push(dependent);
+                }
+            }
+        }
+        Ok(sorted(found))
+    }
+
+    /// The field graph in Graphviz DOT, with an edge from each input to the field reading it
+    #[allow(dead_code)]
+    pub fn to_dot(&self) -> String {
+        let mut lines = vec!["digraph dirfile {".to_string()];
+        let names = self.entry_names();
+        for &name in &names {
+            let entry_type = &self.entry(name).unwrap().entry_type;
+            let shape = match entry_type {
+                crate::EntryType::Raw(_) => "box",
+                _ => "ellipse",
+            };
+            lines.push(format!(
+                "    {} [label={}, shape={}];",
+                dot_id(name),
+                dot_id(&format!("{}\\n{}", name, entry_type.type_name())),
+                shape
+            ));
+        }
+        for &name in &names {
+            for input in self.entry(name).unwrap().entry_type.inputs() {
+                let input = self.resolve_input(input).unwrap();
+                lines.push(format!("    {} -> {};", dot_id(input), dot_id(name)));
+            }
+        }
+        lines.push("}".to_string());
+        lines.join("\n") + "\n"
+    }
+
+    /// every field including metafields, sorted
+    fn entry_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self
+            .entries
+            .values()
+            .flat_map(|entry| {
+                std::iter::once(entry.name.as_str())
+                    .chain(entry.metafields.values().map(|meta| meta.name.as_str()))
+            })
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// the field an input field code reads, which drops any representation suffix
+    fn resolve_input<'a>(&self, field_code: &'a str) -> Option<&'a str> {
+        if self.entry(field_code).is_some() {
+            return Some(field_code);
+        }
+        let (field, _) = crate::Representation::split(field_code)?;
+        self.entry(field).map(|_| field)
+    }
+
+    //depth first through the inputs of `name`, `path` is how we got here
+    fn find_cycles<'a>(
+        &'a self,
+        name: &'a str,
+        path: &mut Vec<&'a str>,
+        done: &mut HashSet<&'a str>,
+        problems: &mut Vec<String>,
+    ) {
+        if done.contains(name) {
+            return;
+        }
+        if let Some(start) = path.iter().position(|&seen| seen == name) {
+            let mut chain = path[start..].to_vec();
+            chain.push(name);
+            problems.push(format!(
+                "fields depend on themselves: {}",
+                chain.join(" -> ")
+            ));
+            return;
+        }
+        path.push(name);
+        for input in self.entry(name).unwrap().entry_type.inputs() {
+            if let Some(input) = self.resolve_input(input) {
+                self.find_cycles(input, path, done, problems);
+            }
+        }
+        path.pop();
+        done.insert(name);
+    }
+}
+
+fn sorted(names: HashSet<&str>) -> Vec<String> {
+    let mut names: Vec<String> = names.into_iter().map(String::from).collect();
+    names.sort();
+    names
+}
+
+/// a quoted DOT identifier, `\n` is left alone so labels can break lines
+fn dot_id(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::{open_error, test_dirfile};
+
+    #[test]
+    fn cycles_and_dangling_inputs() {
+        let error = open_error(
+            "cycles",
+            "r RAW UINT8 1\na LINCOM 2 b 1 0 r 1 0\nb BIT a 0\nc LINCOM c.r 1 0\nd BIT nothing 0\n",
+        );
+        assert_eq!(
+            error,
+            "d reads nothing which is not defined\n\
+             fields depend on themselves: a -> b -> a\n\
+             fields depend on themselves: c -> c"
+        );
+        let dirfile = test_dirfile("acyclic", "r RAW UINT8 1\na LINCOM r 1 0\nb BIT a 0\n");
+        dirfile.validate().unwrap();
+        std::fs::remove_dir_all(&dirfile.root_dir).unwrap();
+    }
+
+    #[test]
+    fn inputs_and_dependents() {
+        let dirfile = test_dirfile(
+            "graph",
+            "r RAW UINT8 1\nc CONST UINT8 2\nl LINCOM 2 r 1 0 r.m 1 0\nb BIT l 0\nx LINCOM 2 b 1 0 c 1 0\n",
+        );
+        assert_eq!(dirfile.inputs("x", false).unwrap(), ["b", "c"]);
+        assert_eq!(dirfile.inputs("x", true).unwrap(), ["b", "c", "l", "r"]);
+        assert!(dirfile.inputs("r", true).unwrap().is_empty());
+        assert_eq!(dirfile.dependents("r", false).unwrap(), ["l"]);
+        assert_eq!(dirfile.dependents(".r", true).unwrap(), ["b", "l", "x"]);
+        assert!(dirfile.dependents("x", true).unwrap().is_empty());
+        assert_eq!(
+            dirfile.inputs("nothing", false).unwrap_err().to_string(),
+            "no field named nothing"
+        );
+        assert!(dirfile.dependents("nothing", true).is_err());
+        let dot = dirfile.to_dot();
+        assert!(dot.starts_with("digraph dirfile {\n"));
+        assert!(dot.contains("    \"r\" [label=\"r\\nRAW\", shape=box];\n"));
+        assert!(dot.contains("    \"r\" -> \"l\";\n"));
+        assert!(dot.contains("    \"b\" -> \"x\";\n"));
+        std::fs::remove_dir_all(&dirfile.root_dir).unwrap();
+    }
+}
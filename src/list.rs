@@ -0,0 +1,127 @@
+impl crate::Dirfile {
+    /// names of all visible fields, sorted
+    #[allow(dead_code)]
+    pub fn field_list(&self) -> Vec<String> {
+        self.entry_list(false)
+    }
+    /// names of all fields, sorted, including hidden ones if asked for
+    #[allow(dead_code)]
+    pub fn entry_list(&self, include_hidden: bool) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .entries
+            .values()
+            .filter(|entry| include_hidden || !entry.hidden)
+            .map(|entry| entry.name.clone())
+            .collect();
+        names.sort();
+        names
+    }
+    /// visible fields directly inside `namespace` (not its sub-namespaces), sorted, with their
+    /// full names. The null namespace is ""
+    #[allow(dead_code)]
+    pub fn field_list_in(&self, namespace: &str) -> Vec<String> {
+        self.field_list()
+            .into_iter()
+            .filter(|name| namespace_of(name) == namespace)
+            .collect()
+    }
+    /// every namespace holding a visible field, sorted
+    #[allow(dead_code)]
+    pub fn namespace_list(&self) -> Vec<String> {
+        let mut namespaces: Vec<String> = self
+            .field_list()
+            .iter()
+            .map(|name| namespace_of(name).to_string())
+            .filter(|namespace| !namespace.is_empty())
+            .collect();
+        namespaces.sort();
+        namespaces.dedup();
+        namespaces
+    }
+    /// names of the metafields of `parent`, sorted, without the `parent/` prefix
+    #[allow(dead_code)]
+    pub fn metafield_list(&self, parent: &str) -> crate::Result<Vec<String>> {
+        let entry = self
+            .entry(parent)
+            .ok_or_else(|| format!("no field named {}", parent))?;
+        let mut names: Vec<String> = entry.metafields.keys().cloned().collect();
+        names.sort();
+        Ok(names)
+    }
+    /// whether `name` was hidden by /HIDDEN
+    #[allow(dead_code)]
+    pub fn hidden(&self, name: &str) -> crate::Result<bool> {
+        let entry = self
+            .entry(name)
+            .ok_or_else(|| format!("no field named {}", name))?;
+        Ok(entry.hidden)
+    }
+}
+
+fn namespace_of(name: &str) -> &str {
+    name.rsplit_once('.').map_or("", |(namespace, _)| namespace)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::{open_error, test_dirfile};
+
+    #[test]
+    fn hidden_fields() {
+        let dirfile = test_dirfile(
+            "hidden",
+            "x RAW UINT8 1\nh LINCOM x 2 0\nl LINCOM h 1 1\n/HIDDEN h\n",
+        );
+        assert_eq!(dirfile.field_list(), ["l", "x"]);
+        assert_eq!(dirfile.entry_list(true), ["h", "l", "x"]);
+        assert!(dirfile.hidden("h").unwrap());
+        assert!(!dirfile.hidden("x").unwrap());
+        assert_eq!(
+            dirfile.hidden("nope").unwrap_err().to_string(),
+            "no field named nope"
+        );
+        //hidden fields can still be read, directly or as inputs
+        dirfile.putdata("x", 0, 0, &[1u8, 2]).unwrap();
+        assert_eq!(dirfile.getdata::<u8>("h", 0, 0, 0, 2).unwrap(), [2, 4]);
+        assert_eq!(dirfile.getdata::<u8>("l", 0, 0, 0, 2).unwrap(), [3, 5]);
+        std::fs::remove_dir_all(&dirfile.root_dir).unwrap();
+    }
+
+    #[test]
+    fn hidden_errors() {
+        let error = open_error("hidden-arg", "x RAW UINT8 1\n/HIDDEN\n");
+        assert!(error.contains("/HIDDEN needs a field"), "{}", error);
+        let error = open_error("hidden-early", "/HIDDEN x\nx RAW UINT8 1\n");
+        assert!(
+            error.contains("/HIDDEN x before it is defined"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn metafields() {
+        let dirfile = test_dirfile(
+            "meta",
+            "x RAW FLOAT64 1\nx/scale CONST FLOAT64 2.5\n/META x units STRING V\n\
+             y RAW FLOAT64 1\n",
+        );
+        assert_eq!(dirfile.metafield_list("x").unwrap(), ["scale", "units"]);
+        assert_eq!(dirfile.metafield_list(".x").unwrap(), ["scale", "units"]);
+        assert!(dirfile.metafield_list("y").unwrap().is_empty());
+        assert!(dirfile.metafield_list("nope").is_err());
+        assert_eq!(dirfile.getstring("x/units").unwrap(), "V");
+        assert_eq!(dirfile.getconstant::<f64>("x/scale").unwrap(), 2.5);
+        //metafields are not fields of their own
+        assert_eq!(dirfile.field_list(), ["x", "y"]);
+        std::fs::remove_dir_all(&dirfile.root_dir).unwrap();
+    }
+
+    #[test]
+    fn metafield_errors() {
+        let error = open_error("meta-early", "x/units STRING V\nx RAW UINT8 1\n");
+        assert!(error.contains("before its parent is defined"), "{}", error);
+        let error = open_error("meta-raw", "x RAW UINT8 1\nx/raw RAW UINT8 1\n");
+        assert!(error.contains("cannot be RAW"), "{}", error);
+    }
+}
@@ -1,23 +1,34 @@
-use num::cast::AsPrimitive;
+// the library is still growing inside the demo binary, the parts of its API that main does not
+// call are marked #[allow(dead_code)]
+use num::Complex;
 use std::{
-    any::TypeId,
     collections::HashMap,
     error::Error,
-    io::{BufRead, Read, Seek, Write},
-    ops::Add,
+    io::Write,
+    ops::{Add, Mul},
 };
 type Result<T> = std::result::Result<T, Box<dyn Error>>;
 
+mod cache;
+mod datalock;
 mod format;
 mod getdata;
+mod graph;
+mod list;
+mod metaflush;
+mod position;
 mod putdata;
+mod rawfile;
+mod transaction;
 
 #[derive(Debug)]
 struct Entry {
     entry_type: EntryType,
     name: String,
-    dirfile_options: DirfileOptions,
+    fragment_index: usize,
     dirfile_path: std::path::PathBuf,
+    hidden: bool, //left out of field lists but otherwise usable
+    metafields: HashMap<String, Entry>,
 }
 
 #[derive(Debug)]
@@ -25,45 +36,269 @@ struct EntryRaw {
     spf: u32,
     data_type: RawTypes,
 }
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum RawTypes {
+    Uint8,
+    Int8,
+    Uint16,
+    Int16,
     Uint32,
+    Int32,
     Uint64,
+    Int64,
+    Float32,
     Float64,
+    Complex64,
+    Complex128,
 }
 
-impl From<&str> for RawTypes {
-    fn from(value: &str) -> Self {
-        match value {
+impl RawTypes {
+    /// bytes per sample on disk
+    fn size(&self) -> usize {
+        match self {
+            RawTypes::Uint8 | RawTypes::Int8 => 1,
+            RawTypes::Uint16 | RawTypes::Int16 => 2,
+            RawTypes::Uint32 | RawTypes::Int32 | RawTypes::Float32 => 4,
+            RawTypes::Uint64 | RawTypes::Int64 | RawTypes::Float64 | RawTypes::Complex64 => 8,
+            RawTypes::Complex128 => 16,
+        }
+    }
+    /// bytes of one empty sample, NaN for floats and zero otherwise
+    fn padding(&self, endian: Endian) -> Vec<u8> {
+        let mut buf = vec![0; self.size()];
+        self.encode(endian, f64::NAN, &mut buf);
+        buf
+    }
+    /// whether samples on disk in `endian` differ from native ones
+    fn needs_swap(&self, endian: Endian) -> bool {
+        let arm_words = matches!(self, RawTypes::Float64 | RawTypes::Complex128);
+        endian.is_big() != cfg!(target_endian = "big") || (arm_words && endian.is_arm())
+    }
+    /// bring samples between their on disk byte order and the native one, in place.
+    /// The conversion is its own inverse so it is used for reading and writing
+    fn swap(&self, endian: Endian, buf: &mut [u8]) {
+        if !self.needs_swap(endian) {
+            return;
+        }
+        //complex samples are two words that are swapped separately
+        let word = match self {
+            RawTypes::Complex64 => 4,
+            RawTypes::Complex128 => 8,
+            _ => self.size(),
+        };
+        let arm_words = matches!(self, RawTypes::Float64 | RawTypes::Complex128);
+        for word in buf.chunks_exact_mut(word) {
+            if arm_words && endian.is_arm() {
+                //old ARM floating point stores the two halves of a double the other way round
+                word.rotate_left(4);
+            }
+            if endian.is_big() != cfg!(target_endian = "big") {
+                word.reverse();
+            }
+        }
+    }
+    /// read one sample of this type already in native byte order
+    fn decode_native<T: DataType>(&self, buf: &[u8]) -> T {
+        match self {
+            RawTypes::Uint8 => T::from_u64(buf[0].into()),
+            RawTypes::Int8 => T::from_i64((buf[0] as i8).into()),
+            RawTypes::Uint16 => T::from_u64(u16::from_ne_bytes(buf.try_into().unwrap()).into()),
+            RawTypes::Int16 => T::from_i64(i16::from_ne_bytes(buf.try_into().unwrap()).into()),
+            RawTypes::Uint32 => T::from_u64(u32::from_ne_bytes(buf.try_into().unwrap()).into()),
+            RawTypes::Int32 => T::from_i64(i32::from_ne_bytes(buf.try_into().unwrap()).into()),
+            RawTypes::Uint64 => T::from_u64(u64::from_ne_bytes(buf.try_into().unwrap())),
+            RawTypes::Int64 => T::from_i64(i64::from_ne_bytes(buf.try_into().unwrap())),
+            RawTypes::Float32 => T::from_f64(f32::from_ne_bytes(buf.try_into().unwrap()).into()),
+            RawTypes::Float64 => T::from_f64(f64::from_ne_bytes(buf.try_into().unwrap())),
+            RawTypes::Complex64 => T::from_complex(Complex::new(
+                f32::from_ne_bytes(buf[..4].try_into().unwrap()).into(),
+                f32::from_ne_bytes(buf[4..].try_into().unwrap()).into(),
+            )),
+            RawTypes::Complex128 => T::from_complex(Complex::new(
+                f64::from_ne_bytes(buf[..8].try_into().unwrap()),
+                f64::from_ne_bytes(buf[8..].try_into().unwrap()),
+            )),
+        }
+    }
+    /// write one sample of this type into `buf`
+    fn encode<T: DataType>(&self, endian: Endian, value: T, buf: &mut [u8]) {
+        match self {
+            RawTypes::Uint8 => buf[0] = value.to_u64() as u8,
+            RawTypes::Int8 => buf[0] = value.to_i64() as u8,
+            RawTypes::Uint16 => buf.copy_from_slice(&(value.to_u64() as u16).to_ne_bytes()),
+            RawTypes::Int16 => buf.copy_from_slice(&(value.to_i64() as i16).to_ne_bytes()),
+            RawTypes::Uint32 => buf.copy_from_slice(&(value.to_u64() as u32).to_ne_bytes()),
+            RawTypes::Int32 => buf.copy_from_slice(&(value.to_i64() as i32).to_ne_bytes()),
+            RawTypes::Uint64 => buf.copy_from_slice(&value.to_u64().to_ne_bytes()),
+            RawTypes::Int64 => buf.copy_from_slice(&value.to_i64().to_ne_bytes()),
+            RawTypes::Float32 => buf.copy_from_slice(&(value.to_f64() as f32).to_ne_bytes()),
+            RawTypes::Float64 => buf.copy_from_slice(&value.to_f64().to_ne_bytes()),
+            RawTypes::Complex64 => {
+                let value = value.to_complex();
+                buf[..4].copy_from_slice(&(value.re as f32).to_ne_bytes());
+                buf[4..].copy_from_slice(&(value.im as f32).to_ne_bytes());
+            }
+            RawTypes::Complex128 => {
+                let value = value.to_complex();
+                buf[..8].copy_from_slice(&value.re.to_ne_bytes());
+                buf[8..].copy_from_slice(&value.im.to_ne_bytes());
+            }
+        }
+        self.swap(endian, buf);
+    }
+    /// append `data` to `buf` as samples of this type
+    fn encode_all<T: DataType>(&self, endian: Endian, data: &[T], buf: &mut Vec<u8>) {
+        let start = buf.len();
+        buf.resize(start + data.len() * self.size(), 0);
+        for (&value, buf) in data.iter().zip(buf[start..].chunks_exact_mut(self.size())) {
+            self.encode(endian, value, buf);
+        }
+    }
+    fn is_integer(&self) -> bool {
+        !matches!(
+            self,
+            RawTypes::Float32 | RawTypes::Float64 | RawTypes::Complex64 | RawTypes::Complex128
+        )
+    }
+}
+
+/// a RAW type name, or one of the one letter codes from before Standards Version 5
+impl TryFrom<&str> for RawTypes {
+    type Error = Box<dyn Error>;
+    fn try_from(value: &str) -> Result<Self> {
+        let raw_type = match format::expand_type_code(value) {
+            "UINT8" => RawTypes::Uint8,
+            "INT8" => RawTypes::Int8,
+            "UINT16" => RawTypes::Uint16,
+            "INT16" => RawTypes::Int16,
             "UINT32" => RawTypes::Uint32,
+            "INT32" => RawTypes::Int32,
             "UINT64" => RawTypes::Uint64,
+            "INT64" => RawTypes::Int64,
+            "FLOAT32" => RawTypes::Float32,
             "FLOAT64" => RawTypes::Float64,
-            _ => panic!("Unknown raw type {}", value),
-        }
+            "COMPLEX64" => RawTypes::Complex64,
+            "COMPLEX128" => RawTypes::Complex128,
+            _ => return Err(format!("Unknown raw type {}", value).into()),
+        };
+        Ok(raw_type)
     }
 }
 
 #[derive(Debug)]
 struct EntryBit {
     start_bit: u32,
-    num_bits: u32,
+    num_bits: u32, //at least 1, and start_bit + num_bits is at most 64
     parent_field: String,
 }
 
+impl EntryBit {
+    /// the bits of the parent this field is made of, in place
+    fn mask(&self) -> u64 {
+        u64::MAX >> (u64::BITS - self.num_bits) << self.start_bit
+    }
+}
+
 #[derive(Debug)]
-struct EntryLincom {
+struct LincomTerm {
     parent_field: String,
-    m: f64,                              //should be complex
-    b: f64,                              //should be complex
-    next_term: Option<Box<EntryLincom>>, //recurse
+    m: Complex<f64>,
+    b: Complex<f64>,
+}
+
+/// sum of 1 to 3 terms `m * parent_field + b`, sampled at the rate of the first term
+#[derive(Debug)]
+struct EntryLincom {
+    terms: Vec<LincomTerm>,
+}
+
+impl EntryLincom {
+    fn is_complex(&self) -> bool {
+        self.terms
+            .iter()
+            .any(|term| term.m.im != 0.0 || term.b.im != 0.0)
+    }
 }
 
 #[derive(Debug)]
 struct EntryLinterp {
     parent_field: String,
-    lookup_table_path: std::path::PathBuf,
-    x: Vec<f64>, //should be complex
-    y: Vec<f64>, //should be complex
+    lookup_table_path: std::path::PathBuf, //joined to the fragment directory by add_entry
+    table: std::sync::OnceLock<LinterpTable>, //read the first time the field is
+}
+
+#[derive(Debug)]
+struct LinterpTable {
+    x: Vec<f64>,          //the independent axis is always real
+    y: Vec<Complex<f64>>, //in the order of x
+}
+
+impl EntryLinterp {
+    /// the lookup table, read from its file the first time it is needed. A table that cannot
+    /// be read is tried again next time
+    fn table(&self) -> Result<&LinterpTable> {
+        if let Some(table) = self.table.get() {
+            return Ok(table);
+        }
+        let table = load_table(&self.lookup_table_path)?;
+        Ok(self.table.get_or_init(|| table))
+    }
+}
+
+/// read a two column LINTERP lookup table
+fn load_table(path: &std::path::Path) -> Result<LinterpTable> {
+    let table = std::fs::read_to_string(path)
+        .map_err(|e| format!("cannot read LINTERP table {:?}: {}", path, e))?;
+    let mut rows = Vec::new();
+    for line in table.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut columns = line.split_whitespace();
+        match (columns.next(), columns.next()) {
+            (Some(x), Some(y)) => rows.push((x.parse::<f64>()?, parse_complex(y)?)),
+            _ => return Err(format!("bad line {:?} in LINTERP table {:?}", line, path).into()),
+        }
+    }
+    if rows.is_empty() {
+        return Err(format!("LINTERP table {:?} is empty", path).into());
+    }
+    rows.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let (x, y) = rows.into_iter().unzip();
+    Ok(LinterpTable { x, y })
+}
+
+#[derive(Debug)]
+struct EntryCarray {
+    values: Vec<f64>,
+}
+
+#[derive(Debug)]
+struct EntrySarray {
+    values: Vec<String>,
+}
+
+#[derive(Debug)]
+struct EntryConst {
+    value: Complex<f64>,
+}
+
+#[derive(Debug)]
+struct EntryString {
+    value: String,
+}
+
+#[derive(Debug)]
+struct EntryIndir {
+    parent_field: String, //index vector
+    carray_field: String,
+}
+
+#[derive(Debug)]
+struct EntrySindir {
+    parent_field: String, //index vector
+    sarray_field: String,
 }
 
 #[derive(Debug)]
@@ -72,53 +307,419 @@ enum EntryType {
     Bit(EntryBit),
     Lincom(EntryLincom),
     Linterp(EntryLinterp),
+    Const(EntryConst),
+    String(EntryString),
+    Carray(EntryCarray),
+    Sarray(EntrySarray),
+    Indir(EntryIndir),
+    Sindir(EntrySindir),
 }
 
+/// A dirfile can be shared between threads. Reads run side by side, writes and the cache of
+/// open RAW files are behind a lock
 struct Dirfile {
     entries: HashMap<String, Entry>,
     root_dir: std::path::PathBuf,
-    fragments: HashMap<String, Dirfile>,
+    fragments: Vec<Fragment>, //the root format file is fragment 0
+    reference: Option<String>,
+    raw_files: std::sync::Mutex<rawfile::RawFiles>, //open RAW files, see rawfile.rs
+    //held for reading while RAW data is read or viewed and for writing while it is written, so
+    //the maps being read never change underneath the reader
+    data_lock: datalock::DataLock,
+    field_cache: Option<cache::FieldCache>, //kept between calls when turned on
+    positions: std::sync::Mutex<HashMap<String, usize>>, //see position.rs
+    mode: OpenMode,
+    #[allow(dead_code)] //only held, closing it drops the lock
+    lock: Option<std::fs::File>, //the root directory, locked for as long as it is open
+    leftover_temp_files: Vec<std::path::PathBuf>, //see leftover_temp_files
+}
+
+/// How a dirfile is opened, which decides the advisory lock taken on its directory. The locks
+/// only keep out other processes that also take them
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OpenMode {
+    /// no lock and nothing can be written, for following a dirfile while another process
+    /// writes it. Nothing stops that process committing a transaction while it is read, so
+    /// its fields can be seen part way through the commit. RAW files are read rather than
+    /// mapped, as another process may shorten them at any time
+    ReadOnly,
+    /// a shared lock, nothing can be written and no one else can open it exclusively, so no
+    /// other process can be committing a transaction while it is open
+    Shared,
+    /// an exclusive lock, the only mode that can write
+    Exclusive,
+}
+
+/// Everything decided when a dirfile is opened. The default only reads and takes a shared lock
+/// so it never sees a commit part way through, writers ask for OpenMode::Exclusive
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct OpenFlags {
+    mode: OpenMode,
+    open_file_limit: usize, //see set_open_file_limit
+    field_cache: bool,      //see set_field_cache
+}
+
+impl Default for OpenFlags {
+    fn default() -> OpenFlags {
+        OpenFlags {
+            mode: OpenMode::Shared,
+            open_file_limit: rawfile::DEFAULT_OPEN_FILES,
+            field_cache: false,
+        }
+    }
+}
+
+/// A format file, either the root one or one pulled in by /INCLUDE
+#[derive(Debug)]
+struct Fragment {
+    format_path: std::path::PathBuf,
+    dirfile_options: DirfileOptions,
+    #[allow(dead_code)]
+    namespace: String, //root namespace given by the /INCLUDE, empty for the null namespace
+    lines: Vec<format::Line>,
 }
 
+//getdata_many relies on this
+const _: fn() = || {
+    fn send_sync<T: Send + Sync>() {}
+    send_sync::<Dirfile>();
+};
+
 #[derive(Debug, Clone, Copy)]
 struct DirfileOptions {
     pub version: u32,
     pub endian: Endian,
+    #[allow(dead_code)] //no encodings are supported yet
     pub encoding: Option<Encoding>,
+    pub frame_offset: u64,
 }
-#[derive(Debug, Clone, Copy)]
+/// Byte order of RAW files. The ARM variants also swap the 32 bit halves of 64 bit floats
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum Endian {
     Big,
     Little,
+    BigArm,
+    LittleArm,
+}
+
+impl Endian {
+    /// what a fragment without an /ENDIAN uses
+    fn native() -> Endian {
+        if cfg!(target_endian = "big") {
+            Endian::Big
+        } else {
+            Endian::Little
+        }
+    }
+    fn is_big(self) -> bool {
+        matches!(self, Endian::Big | Endian::BigArm)
+    }
+    fn is_arm(self) -> bool {
+        matches!(self, Endian::BigArm | Endian::LittleArm)
+    }
 }
 
-impl From<&str> for Endian {
-    fn from(value: &str) -> Self {
-        match value {
-            "big" => Endian::Big,
-            "little" => Endian::Little,
-            _ => panic!("Unknown endian {}", value),
+/// the arguments of an /ENDIAN directive
+impl TryFrom<&[String]> for Endian {
+    type Error = Box<dyn Error>;
+    fn try_from(value: &[String]) -> Result<Self> {
+        let args: Vec<&str> = value.iter().map(String::as_str).collect();
+        match args.as_slice() {
+            ["big"] => Ok(Endian::Big),
+            ["little"] => Ok(Endian::Little),
+            ["big", "arm"] => Ok(Endian::BigArm),
+            ["little", "arm"] => Ok(Endian::LittleArm),
+            _ => Err(format!("Unknown endian {:?}", value).into()),
         }
     }
 }
 
 #[derive(Debug, Clone, Copy)]
+#[allow(dead_code)]
 enum Encoding {
     Sie,
 }
 
-impl From<&format::FieldDefinition> for EntryType {
-    fn from(value: &format::FieldDefinition) -> Self {
-        match value.field_type.as_str() {
+/// Types that can be returned by getdata and written by putdata
+trait DataType: 'static + Copy + Send + Sync + Mul<Output = Self> + Add<Output = Self> {
+    /// the RAW type with the same in memory layout
+    const RAW_TYPE: RawTypes;
+    fn from_f64(value: f64) -> Self;
+    fn from_u64(value: u64) -> Self;
+    fn from_i64(value: i64) -> Self;
+    /// real types keep only the real part
+    fn from_complex(value: Complex<f64>) -> Self;
+    fn to_f64(self) -> f64;
+    fn to_u64(self) -> u64;
+    fn to_i64(self) -> i64;
+    fn to_complex(self) -> Complex<f64>;
+}
+
+macro_rules! impl_real_data_type {
+    ($($t:ty => $raw_type:ident),*) => {$(
+        impl DataType for $t {
+            const RAW_TYPE: RawTypes = RawTypes::$raw_type;
+            fn from_f64(value: f64) -> Self {
+                value as $t
+            }
+            fn from_u64(value: u64) -> Self {
+                value as $t
+            }
+            fn from_i64(value: i64) -> Self {
+                value as $t
+            }
+            fn from_complex(value: Complex<f64>) -> Self {
+                value.re as $t
+            }
+            fn to_f64(self) -> f64 {
+                self as f64
+            }
+            fn to_u64(self) -> u64 {
+                self as u64
+            }
+            fn to_i64(self) -> i64 {
+                self as i64
+            }
+            fn to_complex(self) -> Complex<f64> {
+                Complex::new(self as f64, 0.0)
+            }
+        }
+    )*};
+}
+impl_real_data_type!(
+    u8 => Uint8,
+    u16 => Uint16,
+    u32 => Uint32,
+    u64 => Uint64,
+    i8 => Int8,
+    i16 => Int16,
+    i32 => Int32,
+    i64 => Int64,
+    f32 => Float32,
+    f64 => Float64
+);
+
+macro_rules! impl_complex_data_type {
+    ($($t:ty => $raw_type:ident),*) => {$(
+        impl DataType for Complex<$t> {
+            const RAW_TYPE: RawTypes = RawTypes::$raw_type;
+            fn from_f64(value: f64) -> Self {
+                Complex::new(value as $t, 0.0)
+            }
+            fn from_u64(value: u64) -> Self {
+                Complex::new(value as $t, 0.0)
+            }
+            fn from_i64(value: i64) -> Self {
+                Complex::new(value as $t, 0.0)
+            }
+            fn from_complex(value: Complex<f64>) -> Self {
+                Complex::new(value.re as $t, value.im as $t)
+            }
+            fn to_f64(self) -> f64 {
+                self.re as f64
+            }
+            fn to_u64(self) -> u64 {
+                self.re as u64
+            }
+            fn to_i64(self) -> i64 {
+                self.re as i64
+            }
+            fn to_complex(self) -> Complex<f64> {
+                Complex::new(self.re as f64, self.im as f64)
+            }
+        }
+    )*};
+}
+impl_complex_data_type!(f32 => Complex64, f64 => Complex128);
+
+/// Representation suffix on a field code, e.g. `field.m` for the modulus
+#[derive(Debug, Clone, Copy)]
+enum Representation {
+    Real,
+    Imaginary,
+    Modulus,
+    Argument,
+    Complex,
+}
+
+impl Representation {
+    /// split `field.x` into the field and its representation, if it has one
+    fn split(field_code: &str) -> Option<(&str, Representation)> {
+        let (field, suffix) = field_code.rsplit_once('.')?;
+        let representation = match suffix {
+            "r" => Representation::Real,
+            "i" => Representation::Imaginary,
+            "m" => Representation::Modulus,
+            "a" => Representation::Argument,
+            "z" => Representation::Complex,
+            _ => return None,
+        };
+        Some((field, representation))
+    }
+    fn apply(self, value: Complex<f64>) -> Complex<f64> {
+        match self {
+            Representation::Real => Complex::new(value.re, 0.0),
+            Representation::Imaginary => Complex::new(value.im, 0.0),
+            Representation::Modulus => Complex::new(value.norm(), 0.0),
+            Representation::Argument => Complex::new(value.arg(), 0.0),
+            Representation::Complex => value,
+        }
+    }
+}
+
+/// parse a real or complex number, complex numbers are written `re;im` or `modulus@argument`
+fn parse_complex(value: &str) -> Result<Complex<f64>> {
+    if let Some((re, im)) = value.split_once(';') {
+        Ok(Complex::new(re.parse()?, im.parse()?))
+    } else if let Some((modulus, argument)) = value.split_once('@') {
+        Ok(Complex::from_polar(modulus.parse()?, argument.parse()?))
+    } else {
+        Ok(Complex::new(value.parse()?, 0.0))
+    }
+}
+
+impl EntryType {
+    /// the field type as written in a format file
+    fn type_name(&self) -> &'static str {
+        match self {
+            EntryType::Raw(_) => "RAW",
+            EntryType::Bit(_) => "BIT",
+            EntryType::Lincom(_) => "LINCOM",
+            EntryType::Linterp(_) => "LINTERP",
+            EntryType::Const(_) => "CONST",
+            EntryType::String(_) => "STRING",
+            EntryType::Carray(_) => "CARRAY",
+            EntryType::Sarray(_) => "SARRAY",
+            EntryType::Indir(_) => "INDIR",
+            EntryType::Sindir(_) => "SINDIR",
+        }
+    }
+    /// the field codes this entry reads from
+    fn inputs(&self) -> Vec<&str> {
+        match self {
+            EntryType::Raw(_)
+            | EntryType::Const(_)
+            | EntryType::String(_)
+            | EntryType::Carray(_)
+            | EntryType::Sarray(_) => vec![],
+            EntryType::Bit(bit) => vec![&bit.parent_field],
+            EntryType::Lincom(lincom) => lincom
+                .terms
+                .iter()
+                .map(|term| term.parent_field.as_str())
+                .collect(),
+            EntryType::Linterp(linterp) => vec![&linterp.parent_field],
+            EntryType::Indir(indir) => vec![&indir.parent_field, &indir.carray_field],
+            EntryType::Sindir(sindir) => vec![&sindir.parent_field, &sindir.sarray_field],
+        }
+    }
+    fn inputs_mut(&mut self) -> Vec<&mut String> {
+        match self {
+            EntryType::Raw(_)
+            | EntryType::Const(_)
+            | EntryType::String(_)
+            | EntryType::Carray(_)
+            | EntryType::Sarray(_) => vec![],
+            EntryType::Bit(bit) => vec![&mut bit.parent_field],
+            EntryType::Lincom(lincom) => lincom
+                .terms
+                .iter_mut()
+                .map(|term| &mut term.parent_field)
+                .collect(),
+            EntryType::Linterp(linterp) => vec![&mut linterp.parent_field],
+            EntryType::Indir(indir) => vec![&mut indir.parent_field, &mut indir.carray_field],
+            EntryType::Sindir(sindir) => vec![&mut sindir.parent_field, &mut sindir.sarray_field],
+        }
+    }
+}
+
+/// The prefix and suffix an /INCLUDE puts on the names of the fields in the fragments it
+/// includes. Field codes in those fragments get them too, so their inputs still match
+#[derive(Debug, Clone, Default)]
+struct Affixes {
+    prefix: String,
+    suffix: String,
+}
+
+impl Affixes {
+    /// the affixes of a fragment included with `prefix` and `suffix` from one with these
+    fn include(&self, prefix: &str, suffix: &str) -> Affixes {
+        Affixes {
+            prefix: format!("{}{}", self.prefix, prefix),
+            suffix: format!("{}{}", suffix, self.suffix),
+        }
+    }
+    /// put the affixes on the field name in a field code. A namespace in front of the name, a
+    /// representation suffix and the name of a metafield are left alone, as are absolute codes
+    fn apply(&self, field_code: &str) -> String {
+        if (self.prefix.is_empty() && self.suffix.is_empty()) || field_code.starts_with('.') {
+            return field_code.to_string();
+        }
+        let (field, metafield) = match field_code.split_once('/') {
+            Some((parent, child)) => (parent, format!("/{}", child)),
+            None => (field_code, String::new()),
+        };
+        let (field, representation) = match Representation::split(field) {
+            Some(_) => {
+                let (field, representation) = field.rsplit_once('.').unwrap();
+                (field, format!(".{}", representation))
+            }
+            None => (field, String::new()),
+        };
+        let (namespace, name) = match field.rsplit_once('.') {
+            Some((namespace, name)) => (format!("{}.", namespace), name),
+            None => (String::new(), field),
+        };
+        format!(
+            "{}{}{}{}{}{}",
+            namespace, self.prefix, name, self.suffix, representation, metafield
+        )
+    }
+}
+
+/// put a field code into `namespace`, a leading `.` makes the code absolute
+fn resolve_namespace(namespace: &str, field_code: &str) -> String {
+    if let Some(absolute) = field_code.strip_prefix('.') {
+        absolute.to_string()
+    } else if namespace.is_empty() {
+        field_code.to_string()
+    } else {
+        format!("{}.{}", namespace, field_code)
+    }
+}
+
+impl TryFrom<&format::FieldDefinition> for EntryType {
+    type Error = Box<dyn Error>;
+    fn try_from(value: &format::FieldDefinition) -> Result<Self> {
+        let entry_type = match value.field_type.as_str() {
             "RAW" => {
-                let data_type = RawTypes::from(value.args[0].as_str());
-                let spf = value.args[1].parse().unwrap();
+                let data_type = RawTypes::try_from(value.arg(0)?)?;
+                let spf = value.arg(1)?.parse()?;
+                if spf == 0 {
+                    return Err(format!("RAW {} has no samples per frame", value.name).into());
+                }
                 EntryType::Raw(EntryRaw { spf, data_type })
             }
             "BIT" => {
-                let start_bit = value.args[1].parse().unwrap();
-                let num_bits = value.args[2].parse().unwrap();
-                let parent_field = value.args[0].clone();
+                let start_bit: u32 = value.arg(1)?.parse()?;
+                //one bit unless it says otherwise
+                let num_bits: u32 = match value.args.get(2) {
+                    Some(num_bits) => num_bits.parse()?,
+                    None => 1,
+                };
+                if num_bits == 0 {
+                    return Err(format!("BIT {} has no bits", value.name).into());
+                }
+                if start_bit as u64 + num_bits as u64 > u64::BITS as u64 {
+                    return Err(format!(
+                        "BIT {} takes bits {} to {}, past bit 63",
+                        value.name,
+                        start_bit,
+                        start_bit as u64 + num_bits as u64 - 1
+                    )
+                    .into());
+                }
+                let parent_field = value.arg(0)?.to_string();
                 EntryType::Bit(EntryBit {
                     start_bit,
                     num_bits,
@@ -126,71 +727,279 @@ impl From<&format::FieldDefinition> for EntryType {
                 })
             }
             "LINCOM" => {
-                let mut args = value.args.clone();
-                if args.len() % 3 != 0 {
-                    args = args[1..].to_vec();
+                //n may be left out, the argument count is then a multiple of 3
+                let args = &value.args;
+                let (n, args) = if args.len().is_multiple_of(3) {
+                    (args.len() / 3, &args[..])
+                } else {
+                    let n: usize = args[0].parse().map_err(|_| {
+                        format!(
+                            "LINCOM {}: expected the number of terms, found {:?}",
+                            value.name, args[0]
+                        )
+                    })?;
+                    (n, &args[1..])
+                };
+                if !(1..=3).contains(&n) {
+                    return Err(format!(
+                        "LINCOM {} has {} terms, there must be 1 to 3",
+                        value.name, n
+                    )
+                    .into());
                 }
-
-                fn make_lincom(args: Vec<String>) -> EntryLincom {
-                    let parent_field = args[0].clone();
-                    let m: f64 = args[1].parse().unwrap();
-                    let b: f64 = args[2].parse().unwrap();
-                    if args.len() > 3 {
-                        let next_term = make_lincom(args[3..].to_vec());
-                        EntryLincom {
-                            parent_field,
-                            m,
-                            b,
-                            next_term: Some(Box::new(next_term)),
-                        }
-                    } else {
-                        EntryLincom {
-                            parent_field,
-                            m,
-                            b,
-                            next_term: None,
-                        }
-                    }
+                if args.len() != 3 * n {
+                    return Err(format!(
+                        "LINCOM {} has {} terms but {} arguments for them, expected {}",
+                        value.name,
+                        n,
+                        args.len(),
+                        3 * n
+                    )
+                    .into());
                 }
-
-                EntryType::Lincom(make_lincom(args))
+                let parse = |x: &str| {
+                    parse_complex(x).map_err(|e| {
+                        format!("LINCOM {}: bad coefficient {:?}: {}", value.name, x, e)
+                    })
+                };
+                let terms = args
+                    .chunks(3)
+                    .map(|term| {
+                        Ok(LincomTerm {
+                            parent_field: term[0].clone(),
+                            m: parse(&term[1])?,
+                            b: parse(&term[2])?,
+                        })
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                EntryType::Lincom(EntryLincom { terms })
             }
-            _ => panic!("Unknown field type {:?}", value),
-        }
+            "LINTERP" => EntryType::Linterp(EntryLinterp {
+                parent_field: value.arg(0)?.to_string(),
+                lookup_table_path: std::path::PathBuf::from(value.arg(1)?),
+                table: std::sync::OnceLock::new(),
+            }),
+            "CONST" => {
+                //first arg is the storage type
+                value.arg(0)?;
+                EntryType::Const(EntryConst {
+                    value: parse_complex(value.arg(1)?)?,
+                })
+            }
+            "STRING" => EntryType::String(EntryString {
+                value: value.arg(0)?.to_string(),
+            }),
+            "CARRAY" => {
+                //first arg is the storage type, values are kept as f64
+                value.arg(0)?;
+                let values = value.args[1..]
+                    .iter()
+                    .map(|x| x.parse())
+                    .collect::<std::result::Result<_, _>>()?;
+                EntryType::Carray(EntryCarray { values })
+            }
+            "SARRAY" => EntryType::Sarray(EntrySarray {
+                values: value.args.clone(),
+            }),
+            "INDIR" => EntryType::Indir(EntryIndir {
+                parent_field: value.arg(0)?.to_string(),
+                carray_field: value.arg(1)?.to_string(),
+            }),
+            "SINDIR" => EntryType::Sindir(EntrySindir {
+                parent_field: value.arg(0)?.to_string(),
+                sarray_field: value.arg(1)?.to_string(),
+            }),
+            _ => return Err(format!("Unknown field type {:?}", value).into()),
+        };
+        Ok(entry_type)
     }
 }
 
 impl Dirfile {
-    fn new(root_dir: std::path::PathBuf) -> Result<Dirfile> {
+    /// Open a dirfile as `flags` say, failing if another process holds a lock that conflicts
+    /// with its mode
+    fn new(root_dir: std::path::PathBuf, flags: OpenFlags) -> Result<Dirfile> {
+        let mode = flags.mode;
+        let lock = match mode {
+            OpenMode::ReadOnly => None,
+            OpenMode::Shared | OpenMode::Exclusive => {
+                let dir = std::fs::File::open(&root_dir)?;
+                let locked = match mode {
+                    OpenMode::Shared => dir.try_lock_shared(),
+                    _ => dir.try_lock(),
+                };
+                match locked {
+                    Ok(()) => Some(dir),
+                    Err(std::fs::TryLockError::WouldBlock) => {
+                        let lock = if mode == OpenMode::Shared {
+                            "a shared"
+                        } else {
+                            "an exclusive"
+                        };
+                        return Err(format!(
+                            "{:?} is locked by another process, cannot take {} lock",
+                            root_dir, lock
+                        )
+                        .into());
+                    }
+                    Err(std::fs::TryLockError::Error(e)) => return Err(e.into()),
+                }
+            }
+        };
+        let mut dirfile = Dirfile {
+            entries: HashMap::new(),
+            root_dir,
+            fragments: Vec::new(),
+            reference: None,
+            raw_files: std::sync::Mutex::new(rawfile::RawFiles::new(flags.open_file_limit)),
+            data_lock: datalock::DataLock::default(),
+            field_cache: flags
+                .field_cache
+                .then(|| cache::FieldCache::with_limit(cache::DEFAULT_FIELD_CACHE_BYTES)),
+            positions: std::sync::Mutex::new(HashMap::new()),
+            mode,
+            lock,
+            leftover_temp_files: Vec::new(),
+        };
         //parse in the format file which should be in the root_dir
-        let format_file = root_dir.join("format");
-        let format_file = std::fs::read_to_string(format_file)?;
-        //split it into
+        dirfile.parse_fragment(
+            dirfile.root_dir.join("format"),
+            String::new(),
+            &Affixes::default(),
+            None,
+        )?;
+        dirfile.validate()?;
+        //the format files themselves are fine, the rename is what makes a write take effect. A
+        //temporary file seen without the exclusive lock may be a write still going on
+        for fragment in &dirfile.fragments {
+            let temp = format_temp_path(&fragment.format_path);
+            if !temp.exists() {
+                continue;
+            }
+            if mode == OpenMode::Exclusive {
+                std::fs::remove_file(&temp)?;
+                sync_dir(temp.parent().unwrap_or(std::path::Path::new(".")))?;
+            } else {
+                dirfile.leftover_temp_files.push(temp);
+            }
+        }
+        //a journal may belong to a commit still going on unless we hold the exclusive lock
+        if mode == OpenMode::Exclusive {
+            dirfile.replay_journal()?;
+        } else {
+            dirfile.check_journal()?;
+        }
+        Ok(dirfile)
+    }
+
+    /// Open a dirfile in `mode` with the other flags left at their defaults
+    fn open(root_dir: std::path::PathBuf, mode: OpenMode) -> Result<Dirfile> {
+        Dirfile::new(
+            root_dir,
+            OpenFlags {
+                mode,
+                ..OpenFlags::default()
+            },
+        )
+    }
+
+    /// Open a dirfile only to read it. No lock is taken and nothing in it is ever created or
+    /// opened for writing, so it can be on a read only filesystem
+    #[allow(dead_code)]
+    fn open_readonly(root_dir: std::path::PathBuf) -> Result<Dirfile> {
+        Dirfile::open(root_dir, OpenMode::ReadOnly)
+    }
+
+    fn parse_fragment(
+        &mut self,
+        format_path: std::path::PathBuf,
+        namespace: String,
+        affixes: &Affixes,
+        version: Option<u32>,
+    ) -> Result<()> {
+        let format_file = std::fs::read_to_string(&format_path)?;
         // println!("original: {}", format_file);
 
-        let (_, parsed) = format::parse_format_file(&format_file).unwrap();
+        //an included fragment without a /VERSION carries on with the version of its parent
+        let version = format::find_version(&format_file)
+            .or(version)
+            .unwrap_or_else(|| format::guess_version(&format_file));
+        let (rest, parsed) = format::parse_format_file(&format_file, version)
+            .map_err(|e| format!("{:?}: {}", format_path, e))?;
+        if let Some(line) = rest.lines().next() {
+            return Err(format!(
+                "{:?}: cannot parse {:?} as Standards Version {}",
+                format_path, line, version
+            )
+            .into());
+        }
         // println!("we parsed {:?}", parsed);
+        let dirfile_path = format_path
+            .parent()
+            .map(std::path::Path::to_path_buf)
+            .unwrap_or_default();
         let mut dirfile_options = DirfileOptions {
-            version: 0,
-            endian: Endian::Big,
+            version,
+            endian: Endian::native(),
             encoding: None,
+            frame_offset: 0,
         };
-        let mut entries = HashMap::new();
-        let mut fragments = HashMap::new();
-        for line in parsed {
+        //these apply to the whole fragment wherever they appear so handle them first
+        for line in &parsed {
+            if let format::Line::Directive(directive, args) = line {
+                if directive.since() > version {
+                    return Err(format!(
+                        "{:?}: /{} needs Standards Version {}, the fragment is version {}",
+                        format_path,
+                        directive.name(),
+                        directive.since(),
+                        version
+                    )
+                    .into());
+                }
+                match directive {
+                    format::Directive::Version => {}
+                    format::Directive::Endian => {
+                        dirfile_options.endian = Endian::try_from(args.as_slice())?;
+                    }
+                    format::Directive::Encoding => match args.first().map(String::as_str) {
+                        Some("none") => {}
+                        _ => {
+                            return Err(format!(
+                                "{:?}: /ENCODING {:?} is not supported",
+                                format_path, args
+                            )
+                            .into())
+                        }
+                    },
+                    format::Directive::FrameOffset => {
+                        let frame_offset = args.first().ok_or_else(|| {
+                            format!("{:?}: /FRAMEOFFSET needs a frame", format_path)
+                        })?;
+                        dirfile_options.frame_offset = frame_offset.parse()?;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        let fragment_index = self.fragments.len();
+        self.fragments.push(Fragment {
+            format_path,
+            dirfile_options,
+            namespace: namespace.clone(),
+            lines: Vec::new(),
+        });
+        //field codes are relative to this until the next /NAMESPACE
+        let mut current_namespace = namespace.clone();
+        for line in &parsed {
             match line {
                 format::Line::Directive(directive, args) => {
                     println!("directive: {:?} with args {:?}", directive, args);
                     match directive {
-                        format::Directive::Version => {
-                            dirfile_options.version = args[0].parse().unwrap();
-                        }
-                        format::Directive::Endian => {
-                            dirfile_options.endian = Endian::from(args[0].as_str());
-                        }
-                        format::Directive::Encoding => {
-                            assert_eq!(args[0].as_str(), "none");
-                        }
+                        format::Directive::Version
+                        | format::Directive::Endian
+                        | format::Directive::Encoding
+                        | format::Directive::FrameOffset => {}
                         format::Directive::Alias => {
                             panic!("alias not implemented");
                         }
@@ -198,58 +1007,679 @@ impl Dirfile {
                             println!("Warning: protect not implemented");
                         }
                         format::Directive::Reference => {
-                            println!("Warning: reference not implemented");
+                            let reference = args.first().ok_or_else(|| {
+                                format!(
+                                    "{:?}: /REFERENCE needs a field",
+                                    self.fragments[fragment_index].format_path
+                                )
+                            })?;
+                            self.reference = Some(resolve_namespace(
+                                &current_namespace,
+                                &affixes.apply(reference),
+                            ));
+                        }
+                        format::Directive::Hidden => {
+                            let hidden = args.first().ok_or_else(|| {
+                                format!(
+                                    "{:?}: /HIDDEN needs a field",
+                                    self.fragments[fragment_index].format_path
+                                )
+                            })?;
+                            let name =
+                                resolve_namespace(&current_namespace, &affixes.apply(hidden));
+                            let entry = self
+                                .entry_mut(&name)
+                                .ok_or_else(|| format!("/HIDDEN {} before it is defined", name))?;
+                            entry.hidden = true;
+                        }
+                        format::Directive::Namespace => {
+                            //relative to the fragment's root, `.` goes back to the root
+                            current_namespace = match args.first().map(String::as_str) {
+                                None | Some(".") => namespace.clone(),
+                                Some(sub) => resolve_namespace(&namespace, sub),
+                            };
+                        }
+                        format::Directive::Meta => {
+                            //same as a `parent/child TYPE ...` field line
+                            if args.len() < 3 {
+                                return Err(format!("/META {:?} is too short", args).into());
+                            }
+                            let field_definition = format::FieldDefinition {
+                                name: format!("{}/{}", args[0], args[1]),
+                                field_type: args[2].clone(),
+                                args: args[3..].to_vec(),
+                            };
+                            self.add_entry(
+                                &field_definition,
+                                fragment_index,
+                                &dirfile_path,
+                                &current_namespace,
+                                affixes,
+                            )?;
                         }
                         format::Directive::Include => {
-                            if args.len() !=0 {
-                                panic!("Does not support include with namespace or su/pre fix");
+                            let format_path = &self.fragments[fragment_index].format_path;
+                            let (file, prefix, suffix) = match args.as_slice() {
+                                [file] => (file, "", ""),
+                                [file, prefix] => (file, prefix.as_str(), ""),
+                                [file, prefix, suffix] => (file, prefix.as_str(), suffix.as_str()),
+                                _ => {
+                                    return Err(format!(
+                                        "{:?}: /INCLUDE {:?} should be a file, a prefix and a \
+                                         suffix",
+                                        format_path, args
+                                    )
+                                    .into())
+                                }
+                            };
+                            if args.len() > 1 && version < 9 {
+                                return Err(format!(
+                                    "{:?}: /INCLUDE with a prefix or suffix needs Standards \
+                                     Version 9, the fragment is version {}",
+                                    format_path, version
+                                )
+                                .into());
                             }
-                            fragments.insert(
-                                args[0].clone(),
-                                Dirfile::new(root_dir.join(args[0].clone())).unwrap(),
-                            );
+                            //from version 10 the prefix can start with a namespace, which is
+                            //relative to the current one
+                            let (include_namespace, prefix) = match prefix.rsplit_once('.') {
+                                Some((sub, prefix)) if version >= 10 => {
+                                    (resolve_namespace(&current_namespace, sub), prefix)
+                                }
+                                _ => (current_namespace.clone(), prefix),
+                            };
+                            self.parse_fragment(
+                                dirfile_path.join(file),
+                                include_namespace,
+                                &affixes.include(prefix, suffix),
+                                Some(version),
+                            )?;
                         }
                     }
                 }
                 format::Line::FieldDefinition(field_definition) => {
                     // println!("field_definition: {:?}", field_definition);
-                    let entry_type = EntryType::from(&field_definition);
-                    let entry = Entry {
-                        entry_type,
-                        name: field_definition.name.clone(),
-                        dirfile_options: (dirfile_options.clone()),
-                        dirfile_path: (root_dir.clone()),
-                    };
-                    println!("entry: {:?}", entry);
-                    entries.insert(field_definition.name.clone(), entry);
+                    match format::field_type_since(&field_definition.field_type) {
+                        Some(since) if since > version => {
+                            return Err(format!(
+                                "{} {} needs Standards Version {}, the fragment is version {}",
+                                field_definition.field_type, field_definition.name, since, version
+                            )
+                            .into());
+                        }
+                        _ => {}
+                    }
+                    self.add_entry(
+                        field_definition,
+                        fragment_index,
+                        &dirfile_path,
+                        &current_namespace,
+                        affixes,
+                    )?;
                 }
             }
         }
+        //kept so metaflush can write the fragment back out
+        self.fragments[fragment_index].lines = parsed;
+        Ok(())
+    }
 
-        return Ok(Dirfile {
-            entries,
-            root_dir,
-            fragments,
-        });
+    fn add_entry(
+        &mut self,
+        field_definition: &format::FieldDefinition,
+        fragment_index: usize,
+        dirfile_path: &std::path::Path,
+        namespace: &str,
+        affixes: &Affixes,
+    ) -> Result<()> {
+        let mut entry_type = EntryType::try_from(field_definition)?;
+        //the table is only read when the field is, a missing one does not stop the open
+        if let EntryType::Linterp(linterp) = &mut entry_type {
+            linterp.lookup_table_path = dirfile_path.join(&linterp.lookup_table_path);
+        }
+        for input in entry_type.inputs_mut() {
+            *input = resolve_namespace(namespace, &affixes.apply(input));
+        }
+        //for metafields only the parent is namespaced
+        let name = resolve_namespace(namespace, &affixes.apply(&field_definition.name));
+        let entry = Entry {
+            entry_type,
+            name: name.clone(),
+            fragment_index,
+            dirfile_path: dirfile_path.to_path_buf(),
+            hidden: false,
+            metafields: HashMap::new(),
+        };
+        println!("entry: {:?}", entry);
+        //metafields are named parent/child and live under their parent
+        if let Some((parent, child)) = name.split_once('/') {
+            if matches!(entry.entry_type, EntryType::Raw(_)) {
+                return Err(format!("metafield {} cannot be RAW", entry.name).into());
+            }
+            let parent = self
+                .entries
+                .get_mut(parent)
+                .ok_or_else(|| format!("metafield {} before its parent is defined", entry.name))?;
+            parent.metafields.insert(child.to_string(), entry);
+            return Ok(());
+        }
+        //without a /REFERENCE the first RAW field is the reference
+        if self.reference.is_none() && matches!(entry.entry_type, EntryType::Raw(_)) {
+            self.reference = Some(name.clone());
+        }
+        self.entries.insert(name, entry);
+        Ok(())
+    }
+
+    /// look up a field or a `parent/child` metafield. Entries are stored under their absolute
+    /// names so a leading `.` changes nothing
+    fn entry(&self, name: &str) -> Option<&Entry> {
+        let name = name.strip_prefix('.').unwrap_or(name);
+        match name.split_once('/') {
+            Some((parent, child)) => self.entries.get(parent)?.metafields.get(child),
+            None => self.entries.get(name),
+        }
+    }
+
+    fn entry_mut(&mut self, name: &str) -> Option<&mut Entry> {
+        let name = name.strip_prefix('.').unwrap_or(name);
+        match name.split_once('/') {
+            Some((parent, child)) => self.entries.get_mut(parent)?.metafields.get_mut(child),
+            None => self.entries.get_mut(name),
+        }
     }
 
+    /// Temporary format files found when the dirfile was opened, left by an interrupted write or
+    /// one still going on in another process. They do not affect reading and are removed the
+    /// next time the dirfile is opened exclusively
+    #[allow(dead_code)]
+    fn leftover_temp_files(&self) -> &[std::path::PathBuf] {
+        &self.leftover_temp_files
+    }
+
+    /// fail unless the dirfile was opened for writing
+    fn check_writable(&self) -> Result<()> {
+        match self.mode {
+            OpenMode::Exclusive => Ok(()),
+            OpenMode::ReadOnly => Err(format!("{:?} is open read only", self.root_dir).into()),
+            OpenMode::Shared => Err(format!(
+                "{:?} is open with a shared lock, writing needs an exclusive one",
+                self.root_dir
+            )
+            .into()),
+        }
+    }
+
+    /// replace the `/NAME` line of a fragment's format file, or add one if it has none
+    fn write_directive(&self, fragment_index: usize, name: &str, value: &str) -> Result<()> {
+        let path = &self.fragments[fragment_index].format_path;
+        let format_file = std::fs::read_to_string(path)?;
+        let directive = format!("/{}", name);
+        let line = format!("{} {}", directive, value);
+        let mut found = false;
+        let mut lines: Vec<String> = format_file
+            .lines()
+            .map(|existing| {
+                if existing.split_whitespace().next() == Some(directive.as_str()) {
+                    found = true;
+                    line.clone()
+                } else {
+                    existing.to_string()
+                }
+            })
+            .collect();
+        if !found {
+            lines.push(line);
+        }
+        write_format_file(path, &(lines.join("\n") + "\n"))
+    }
+}
+
+/// added to the name of a format file for the temporary file it is written to
+const FORMAT_TEMP_SUFFIX: &str = ".grabdata-tmp";
+
+fn format_temp_path(format_path: &std::path::Path) -> std::path::PathBuf {
+    let mut temp = format_path.as_os_str().to_owned();
+    temp.push(FORMAT_TEMP_SUFFIX);
+    temp.into()
+}
+
+/// Replace a format file through a temporary file and a rename, so a crash leaves either the
+/// old or the new file and never half of one
+fn write_format_file(format_path: &std::path::Path, contents: &str) -> Result<()> {
+    let temp = format_temp_path(format_path);
+    let mut file = std::fs::File::create(&temp)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    std::fs::rename(&temp, format_path)?;
+    sync_dir(format_path.parent().unwrap_or(std::path::Path::new(".")))?;
+    Ok(())
+}
+
+/// fsync a directory so the files created in, renamed in or removed from it are on disk
+fn sync_dir(dir: &std::path::Path) -> std::io::Result<()> {
+    let dir = if dir.as_os_str().is_empty() {
+        std::path::Path::new(".")
+    } else {
+        dir
+    };
+    std::fs::File::open(dir)?.sync_all()
 }
 
 fn main() {
     println!("Hello, world!");
     let root_dir = std::path::PathBuf::from("data_test");
-    let dirfile = Dirfile::new(root_dir).unwrap();
-    dirfile.putdata("test", 0, 0, vec![1; 100]);
-    let res = dirfile.getdata::<i64>("test", 1, 0, 100, 0);
+    let dirfile = Dirfile::open(root_dir, OpenMode::Exclusive).unwrap();
+    dirfile.putdata("test", 0, 0, &[1; 100]).unwrap();
+    let res = dirfile.getdata::<i64>("test", 1, 0, 100, 0).unwrap();
     println!("res: {:?}", res);
-    dirfile.putdata("test", 0, 0, vec![2; 100]);
-    let res = dirfile.getdata::<i64>("test", 1, 0, 100, 0);
+    dirfile.putdata("test", 0, 0, &[2; 100]).unwrap();
+    let res = dirfile.getdata::<i64>("test", 1, 0, 100, 0).unwrap();
     println!("res: {:?}", res);
-    dirfile.putdata("testuint", 0, 0, vec![3; 100]);
-    let res = dirfile.getdata::<i64>("testuint", 1, 0, 100, 0);
+    dirfile.putdata("testuint", 0, 0, &[3; 100]).unwrap();
+    let res = dirfile.getdata::<i64>("testuint", 1, 0, 100, 0).unwrap();
     println!("res: {:?}", res);
-    let res = dirfile.getdata::<i64>("testbit", 1, 0, 100, 0);
+    let res = dirfile.getdata::<i64>("testbit", 1, 0, 100, 0).unwrap();
     println!("res: {:?}", res);
-    let res = dirfile.getdata::<f64>("testlincom", 1, 0, 100, 0);
+    let res = dirfile.getdata::<f64>("testlincom", 1, 0, 100, 0).unwrap();
     println!("res: {:?}", res);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a directory of its own under the temporary directory, holding `files`
+    pub(crate) fn test_dir(name: &str, files: &[(&str, &str)]) -> std::path::PathBuf {
+        let root_dir =
+            std::env::temp_dir().join(format!("grabdata-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&root_dir);
+        std::fs::create_dir_all(&root_dir).unwrap();
+        for (file, contents) in files {
+            std::fs::write(root_dir.join(file), contents).unwrap();
+        }
+        root_dir
+    }
+
+    /// a dirfile of its own with the format file `format`, opened exclusively
+    pub(crate) fn test_dirfile(name: &str, format: &str) -> Dirfile {
+        Dirfile::open(test_dir(name, &[("format", format)]), OpenMode::Exclusive).unwrap()
+    }
+
+    /// why a dirfile with the format file `format` cannot be opened
+    pub(crate) fn open_error(name: &str, format: &str) -> String {
+        let root_dir = test_dir(name, &[("format", format)]);
+        let error = match Dirfile::open(root_dir.clone(), OpenMode::Exclusive) {
+            Ok(_) => panic!("{} opened", name),
+            Err(error) => error.to_string(),
+        };
+        std::fs::remove_dir_all(root_dir).unwrap();
+        error
+    }
+
+    fn parse(line: &str) -> Result<EntryType> {
+        let mut tokens = line.split_whitespace().map(str::to_string);
+        let definition = format::FieldDefinition {
+            name: tokens.next().unwrap(),
+            field_type: tokens.next().unwrap(),
+            args: tokens.collect(),
+        };
+        EntryType::try_from(&definition)
+    }
+
+    fn parse_error(line: &str) -> String {
+        parse(line).unwrap_err().to_string()
+    }
+
+    const ENDIANS: [Endian; 4] = [
+        Endian::Big,
+        Endian::Little,
+        Endian::BigArm,
+        Endian::LittleArm,
+    ];
+
+    #[test]
+    fn swap_round_trips() {
+        let types = [
+            RawTypes::Uint8,
+            RawTypes::Int8,
+            RawTypes::Uint16,
+            RawTypes::Int16,
+            RawTypes::Uint32,
+            RawTypes::Int32,
+            RawTypes::Uint64,
+            RawTypes::Int64,
+            RawTypes::Float32,
+            RawTypes::Float64,
+            RawTypes::Complex64,
+            RawTypes::Complex128,
+        ];
+        for data_type in types {
+            for endian in ENDIANS {
+                let mut buf = vec![0; data_type.size()];
+                data_type.encode(endian, 100i64, &mut buf);
+                data_type.swap(endian, &mut buf);
+                assert_eq!(
+                    data_type.decode_native::<i64>(&buf),
+                    100,
+                    "{:?} {:?}",
+                    data_type,
+                    endian
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn swap_byte_orders() {
+        let encoded = |data_type: RawTypes, endian, value: f64| {
+            let mut buf = vec![0; data_type.size()];
+            data_type.encode(endian, value, &mut buf);
+            buf
+        };
+        let one = 1.0f64.to_bits().to_be_bytes().to_vec();
+        assert_eq!(encoded(RawTypes::Float64, Endian::Big, 1.0), one);
+        let little: Vec<u8> = one.iter().rev().copied().collect();
+        assert_eq!(encoded(RawTypes::Float64, Endian::Little, 1.0), little);
+        //ARM swaps the 32 bit halves of the double
+        assert_eq!(
+            encoded(RawTypes::Float64, Endian::BigArm, 1.0),
+            [&one[4..], &one[..4]].concat()
+        );
+        assert_eq!(
+            encoded(RawTypes::Float64, Endian::LittleArm, 1.0),
+            [&little[4..], &little[..4]].concat()
+        );
+        //but leaves everything else alone
+        assert_eq!(
+            encoded(RawTypes::Float32, Endian::BigArm, 1.0),
+            1.0f32.to_be_bytes()
+        );
+        assert_eq!(
+            encoded(RawTypes::Uint64, Endian::LittleArm, 1.0),
+            1u64.to_le_bytes()
+        );
+        //the two words of a complex sample are swapped separately
+        let mut buf = vec![0; 8];
+        RawTypes::Complex64.encode(Endian::Big, Complex::new(1.0, 2.0), &mut buf);
+        assert_eq!(buf, [1.0f32.to_be_bytes(), 2.0f32.to_be_bytes()].concat());
+    }
+
+    #[test]
+    fn fragment_byte_orders() {
+        let root_dir = test_dir(
+            "byte-orders",
+            &[
+                ("format", "/ENDIAN big\nb RAW UINT16 1\n/INCLUDE sub\n"),
+                (
+                    "sub",
+                    "/ENDIAN little arm\nd RAW FLOAT64 1\nn RAW UINT16 1\n",
+                ),
+            ],
+        );
+        let dirfile = Dirfile::open(root_dir.clone(), OpenMode::Exclusive).unwrap();
+        dirfile.putdata("b", 0, 0, &[0x0102u16]).unwrap();
+        dirfile.putdata("d", 0, 0, &[1.0f64]).unwrap();
+        dirfile.putdata("n", 0, 0, &[0x0102u16]).unwrap();
+        dirfile.flush().unwrap();
+        //each fragment keeps its own byte order, an included one does not inherit it
+        assert_eq!(std::fs::read(root_dir.join("b")).unwrap(), [1, 2]);
+        assert_eq!(std::fs::read(root_dir.join("n")).unwrap(), [2, 1]);
+        let one = 1.0f64.to_le_bytes();
+        assert_eq!(
+            std::fs::read(root_dir.join("d")).unwrap(),
+            [&one[4..], &one[..4]].concat()
+        );
+        assert_eq!(dirfile.getdata::<u16>("b", 0, 0, 0, 1).unwrap(), [0x0102]);
+        assert_eq!(dirfile.getdata::<f64>("d", 0, 0, 0, 1).unwrap(), [1.0]);
+        let error = open_error("byte-order-bad", "/ENDIAN middle\n");
+        assert!(error.contains("Unknown endian"), "{}", error);
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn lincom_terms() {
+        for line in ["l LINCOM a 2 1", "l LINCOM 1 a 2 1"] {
+            let Ok(EntryType::Lincom(lincom)) = parse(line) else {
+                panic!("{} did not parse", line);
+            };
+            assert_eq!(lincom.terms.len(), 1);
+            assert_eq!(lincom.terms[0].parent_field, "a");
+            assert_eq!(lincom.terms[0].m, Complex::new(2.0, 0.0));
+            assert_eq!(lincom.terms[0].b, Complex::new(1.0, 0.0));
+        }
+        let Ok(EntryType::Lincom(lincom)) = parse("l LINCOM 2 a 2 1 b 3 0") else {
+            panic!("two terms did not parse");
+        };
+        assert_eq!(lincom.terms.len(), 2);
+    }
+
+    #[test]
+    fn lincom_errors() {
+        assert!(parse_error("l LINCOM 2 a 1 0").contains("has 2 terms but 3 arguments"));
+        assert!(parse_error("l LINCOM 1 a 1 0 b 1 0").contains("has 1 terms but 6 arguments"));
+        assert!(parse_error("l LINCOM 4 a 1 0 b 1 0 c 1 0 d").contains("there must be 1 to 3"));
+        assert!(parse_error("l LINCOM a 1 0 b 1 0 c 1 0 d 1 0").contains("there must be 1 to 3"));
+        assert!(parse_error("l LINCOM x a 1 0").contains("expected the number of terms"));
+        assert!(parse_error("l LINCOM a q 0").contains("bad coefficient \"q\""));
+    }
+
+    #[test]
+    fn bit_bits() {
+        let Ok(EntryType::Bit(bit)) = parse("b BIT w 5") else {
+            panic!("BIT without a bit count did not parse");
+        };
+        assert_eq!((bit.start_bit, bit.num_bits), (5, 1));
+        assert_eq!(bit.mask(), 0b100000);
+        let Ok(EntryType::Bit(bit)) = parse("b BIT w 62 2") else {
+            panic!("the top two bits did not parse");
+        };
+        assert_eq!(bit.mask(), 0b11 << 62);
+        assert!(parse_error("b BIT w 3 0").contains("has no bits"));
+        assert!(parse_error("b BIT w 63 2").contains("bits 63 to 64, past bit 63"));
+        assert!(parse_error("b BIT w 4294967295 1").contains("past bit 63"));
+    }
+
+    #[test]
+    fn namespaces() {
+        let dirfile = test_dirfile(
+            "namespaces",
+            "/NAMESPACE ns\nx RAW UINT8 1\nl LINCOM x 2 0\na LINCOM .y 1 1\n\
+             /NAMESPACE .\ny RAW UINT8 1\n",
+        );
+        assert_eq!(dirfile.field_list(), ["ns.a", "ns.l", "ns.x", "y"]);
+        assert_eq!(dirfile.field_list_in("ns"), ["ns.a", "ns.l", "ns.x"]);
+        assert_eq!(dirfile.namespace_list(), ["ns"]);
+        dirfile.putdata("ns.x", 0, 0, &[1u8, 2]).unwrap();
+        dirfile.putdata(".y", 0, 0, &[5u8, 6]).unwrap();
+        //relative codes in the format file are in its namespace, absolute ones are not
+        assert_eq!(dirfile.getdata::<u8>("ns.l", 0, 0, 0, 2).unwrap(), [2, 4]);
+        assert_eq!(dirfile.getdata::<u8>(".ns.a", 0, 0, 0, 2).unwrap(), [6, 7]);
+        assert!(dirfile.getdata::<u8>("x", 0, 0, 0, 2).is_err());
+        std::fs::remove_dir_all(&dirfile.root_dir).unwrap();
+    }
+
+    #[test]
+    fn include_affixes() {
+        let sub = "y RAW UINT8 1\nl LINCOM y 2 0\nm LINCOM y.r 3 0\ny/units STRING V\n";
+        let root_dir = test_dir(
+            "include-affixes",
+            &[
+                (
+                    "format",
+                    "/VERSION 9\n/INCLUDE sub pre\n/INCLUDE sub2 p s\n",
+                ),
+                ("sub", sub),
+                ("sub2", "/INCLUDE sub a b\n"),
+            ],
+        );
+        let dirfile = Dirfile::open(root_dir, OpenMode::Exclusive).unwrap();
+        //the affixes of nested includes go around each other
+        assert_eq!(
+            dirfile.field_list(),
+            ["palbs", "pambs", "paybs", "prel", "prem", "prey"]
+        );
+        assert_eq!(dirfile.getstring("prey/units").unwrap(), "V");
+        assert_eq!(dirfile.getstring("paybs/units").unwrap(), "V");
+        //the inputs of the included fields have the affixes too
+        dirfile.putdata("prey", 0, 0, &[1u8, 2]).unwrap();
+        assert_eq!(dirfile.getdata::<u8>("prel", 0, 0, 0, 2).unwrap(), [2, 4]);
+        assert_eq!(dirfile.getdata::<u8>("prem", 0, 0, 0, 2).unwrap(), [3, 6]);
+        dirfile.putdata("paybs", 0, 0, &[3u8]).unwrap();
+        assert_eq!(dirfile.getdata::<u8>("palbs", 0, 0, 0, 1).unwrap(), [6]);
+        std::fs::remove_dir_all(&dirfile.root_dir).unwrap();
+    }
+
+    #[test]
+    fn include_namespaces() {
+        let root_dir = test_dir(
+            "include-namespaces",
+            &[
+                ("format", "/INCLUDE sub ns.pre\n/INCLUDE sub ns2.\n"),
+                ("sub", "y RAW UINT8 1\nl LINCOM y 2 0\n"),
+            ],
+        );
+        let dirfile = Dirfile::open(root_dir, OpenMode::Exclusive).unwrap();
+        assert_eq!(
+            dirfile.field_list(),
+            ["ns.prel", "ns.prey", "ns2.l", "ns2.y"]
+        );
+        dirfile.putdata("ns2.y", 0, 0, &[4u8]).unwrap();
+        assert_eq!(dirfile.getdata::<u8>("ns2.l", 0, 0, 0, 1).unwrap(), [8]);
+        std::fs::remove_dir_all(&dirfile.root_dir).unwrap();
+    }
+
+    #[test]
+    fn include_errors() {
+        let error = open_error("include-version", "/VERSION 8\n/INCLUDE sub pre\n");
+        assert!(error.contains("needs Standards Version 9"), "{}", error);
+        let error = open_error("include-args", "/INCLUDE sub a b c\n");
+        assert!(
+            error.contains("should be a file, a prefix and a suffix"),
+            "{}",
+            error
+        );
+        let error = open_error("include-none", "/INCLUDE\n");
+        assert!(error.contains("should be a file"), "{}", error);
+    }
+
+    #[test]
+    fn frame_checks() {
+        assert!(parse_error("x RAW UINT8 0").contains("RAW x has no samples per frame"));
+        let error = open_error("frameoffset-arg", "/FRAMEOFFSET\nx RAW UINT8 1\n");
+        assert!(error.contains("/FRAMEOFFSET needs a frame"), "{}", error);
+        let error = open_error("reference-arg", "x RAW UINT8 1\n/REFERENCE\n");
+        assert!(error.contains("/REFERENCE needs a field"), "{}", error);
+        let error = open_error(
+            "reference-type",
+            "x RAW UINT8 1\nl LINCOM x 1 0\n/REFERENCE l\n",
+        );
+        assert!(
+            error.contains("the reference field l is a LINCOM field"),
+            "{}",
+            error
+        );
+        let error = open_error("reference-missing", "x RAW UINT8 1\n/REFERENCE y\n");
+        assert!(
+            error.contains("the reference field y is not defined"),
+            "{}",
+            error
+        );
+        let error = open_error("encoding", "/ENCODING gzip\nx RAW UINT8 1\n");
+        assert!(error.contains("is not supported"), "{}", error);
+    }
+
+    #[test]
+    fn temp_files() {
+        let root_dir = test_dir(
+            "temp-files",
+            &[
+                ("format", "/INCLUDE sub\n"),
+                ("sub", "a RAW UINT8 1\n"),
+                ("sub.grabdata-tmp", "a RAW UI"),
+            ],
+        );
+        //without the exclusive lock the write may still be going on, so it is only reported
+        for mode in [OpenMode::ReadOnly, OpenMode::Shared] {
+            let dirfile = Dirfile::open(root_dir.clone(), mode).unwrap();
+            assert_eq!(
+                dirfile.leftover_temp_files(),
+                [root_dir.join("sub.grabdata-tmp")]
+            );
+        }
+        let mut dirfile = Dirfile::open(root_dir.clone(), OpenMode::Exclusive).unwrap();
+        assert!(dirfile.leftover_temp_files().is_empty());
+        assert!(!root_dir.join("sub.grabdata-tmp").exists());
+        //writes go through a temporary file that is renamed over the format file
+        dirfile.alter_frameoffset(1, 2, false).unwrap();
+        assert!(!root_dir.join("sub.grabdata-tmp").exists());
+        let sub = std::fs::read_to_string(root_dir.join("sub")).unwrap();
+        assert!(sub.contains("/FRAMEOFFSET 2"), "{}", sub);
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn locks() {
+        let root_dir = test_dir("locks", &[("format", "a RAW UINT8 1\n")]);
+        let open = |mode| Dirfile::open(root_dir.clone(), mode);
+        let writer = open(OpenMode::Exclusive).unwrap();
+        let error = open(OpenMode::Exclusive).err().unwrap().to_string();
+        assert!(error.contains("cannot take an exclusive lock"), "{}", error);
+        let error = open(OpenMode::Shared).err().unwrap().to_string();
+        assert!(error.contains("cannot take a shared lock"), "{}", error);
+        //read only opens take no lock
+        let reader = open(OpenMode::ReadOnly).unwrap();
+        drop(writer);
+        let shared = [
+            open(OpenMode::Shared).unwrap(),
+            open(OpenMode::Shared).unwrap(),
+        ];
+        assert!(open(OpenMode::Exclusive).is_err());
+        let error = shared[0]
+            .putdata("a", 0, 0, &[1u8])
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.contains("writing needs an exclusive one"),
+            "{}",
+            error
+        );
+        drop(shared);
+        drop(reader);
+        drop(open(OpenMode::Exclusive).unwrap());
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn read_only() {
+        let root_dir = test_dir(
+            "read-only",
+            &[("format", "a RAW UINT8 1\nl LINCOM a 2 0\n")],
+        );
+        let files = || {
+            let mut files: Vec<_> = std::fs::read_dir(&root_dir)
+                .unwrap()
+                .map(|file| file.unwrap().file_name())
+                .collect();
+            files.sort();
+            files
+        };
+        let before = files();
+        let mut dirfile = Dirfile::open_readonly(root_dir.clone()).unwrap();
+        let read_only = |result: Result<usize>| {
+            let error = result.unwrap_err().to_string();
+            assert!(error.contains("is open read only"), "{}", error);
+        };
+        read_only(dirfile.putdata("a", 0, 0, &[1u8]));
+        read_only(dirfile.putdata("l", 0, 0, &[1u8]));
+        read_only(dirfile.putdata_here("a", &[1u8]));
+        read_only(dirfile.putdata_append("a", &[1u8]));
+        read_only(dirfile.transaction().putdata("a", 0, 0, &[1u8]));
+        assert!(dirfile.alter_frameoffset(0, 1, true).is_err());
+        assert!(dirfile.metaflush().is_err());
+        //reading a field that was never written does not create its file
+        assert!(dirfile.getdata::<u8>("l", 0, 0, 0, 1).is_err());
+        assert_eq!(files(), before);
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+}
@@ -0,0 +1,101 @@
+use crate::format::{self, Directive, Line};
+
+impl crate::Dirfile {
+    /// Write every fragment's format file back out in its own Standards Version. Comments in
+    /// the original files are not kept
+    #[allow(dead_code)]
+    pub fn metaflush(&self) -> crate::Result<()> {
+        self.check_writable()?;
+        for (fragment_index, fragment) in self.fragments.iter().enumerate() {
+            let version = fragment.dirfile_options.version;
+            let format_file = self.format_fragment(fragment_index, version)?;
+            crate::write_format_file(&fragment.format_path, &format_file)?;
+        }
+        Ok(())
+    }
+
+    /// Write every fragment's format file in Standards Version `version`. Nothing is written if
+    /// any fragment uses something that version cannot express
+    #[allow(dead_code)]
+    pub fn metaflush_version(&mut self, version: u32) -> crate::Result<()> {
+        self.check_writable()?;
+        if version > format::STANDARDS_VERSION {
+            return Err(format!(
+                "cannot write Standards Version {}, the newest is {}",
+                version,
+                format::STANDARDS_VERSION
+            )
+            .into());
+        }
+        let format_files = (0..self.fragments.len())
+            .map(|fragment_index| self.format_fragment(fragment_index, version))
+            .collect::<crate::Result<Vec<_>>>()?;
+        for (fragment, format_file) in self.fragments.iter_mut().zip(format_files) {
+            crate::write_format_file(&fragment.format_path, &format_file)?;
+            fragment.dirfile_options.version = version;
+        }
+        Ok(())
+    }
+
+    fn format_fragment(&self, fragment_index: usize, version: u32) -> crate::Result<String> {
+        let fragment = &self.fragments[fragment_index];
+        let dirfile_options = fragment.dirfile_options;
+        let mut lines = vec![
+            "# This is a dirfile format file.".to_string(),
+            "# It was written by grabdata.".to_string(),
+        ];
+        //the fragment wide directives come from the current options, not what was parsed
+        if version >= 5 {
+            lines.push(
+                Line::Directive(Directive::Version, vec![version.to_string()])
+                    .to_format(version)?,
+            );
+            let mut endian = vec![String::from(if dirfile_options.endian.is_big() {
+                "big"
+            } else {
+                "little"
+            })];
+            if dirfile_options.endian.is_arm() {
+                endian.push("arm".to_string());
+            }
+            lines.push(Line::Directive(Directive::Endian, endian).to_format(version)?);
+        } else if dirfile_options.endian != crate::Endian::native() {
+            return Err(format!(
+                "{:?}: {:?} data needs /ENDIAN from Standards Version 5",
+                fragment.format_path, dirfile_options.endian
+            )
+            .into());
+        }
+        if dirfile_options.frame_offset != 0 {
+            lines.push(
+                Line::Directive(
+                    Directive::FrameOffset,
+                    vec![dirfile_options.frame_offset.to_string()],
+                )
+                .to_format(version)?,
+            );
+        }
+        if version >= 6 {
+            lines.push(
+                Line::Directive(Directive::Encoding, vec!["none".to_string()])
+                    .to_format(version)?,
+            );
+        }
+        for line in &fragment.lines {
+            match line {
+                Line::Directive(
+                    Directive::Version
+                    | Directive::Endian
+                    | Directive::FrameOffset
+                    | Directive::Encoding,
+                    _,
+                ) => {}
+                line => lines.push(
+                    line.to_format(version)
+                        .map_err(|e| format!("{:?}: {}", fragment.format_path, e))?,
+                ),
+            }
+        }
+        Ok(lines.join("\n") + "\n")
+    }
+}
@@ -0,0 +1,81 @@
+impl crate::Dirfile {
+    /// Move the I/O position of a vector field used by getdata_here and putdata_here
+    #[allow(dead_code)]
+    pub fn seek(&self, name: &str, frame: usize, sample: usize) -> crate::Result<()> {
+        let entry_type = match self.entry(name) {
+            Some(entry) => &entry.entry_type,
+            None => {
+                let (field, _) = crate::Representation::split(name)
+                    .ok_or_else(|| format!("no field named {}", name))?;
+                &self
+                    .entry(field)
+                    .ok_or_else(|| format!("no field named {}", name))?
+                    .entry_type
+            }
+        };
+        if matches!(
+            entry_type,
+            crate::EntryType::Const(_)
+                | crate::EntryType::String(_)
+                | crate::EntryType::Carray(_)
+                | crate::EntryType::Sarray(_)
+        ) {
+            return Err(format!("{} is a scalar field and has no position", name).into());
+        }
+        let position = frame * self.spf(name)? as usize + sample;
+        self.positions
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), position);
+        Ok(())
+    }
+    /// The I/O position of a field in samples, fields start at sample 0
+    #[allow(dead_code)]
+    pub fn tell(&self, name: &str) -> usize {
+        self.positions
+            .lock()
+            .unwrap()
+            .get(name)
+            .copied()
+            .unwrap_or(0)
+    }
+    /// move the position of `name` on by `samples`
+    pub(crate) fn advance(&self, name: &str, samples: usize) {
+        *self
+            .positions
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_default() += samples;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::test_dirfile;
+
+    #[test]
+    fn positions() {
+        let dirfile = test_dirfile(
+            "positions",
+            "x RAW UINT16 2\ny RAW UINT16 1\nc CONST UINT8 1\n",
+        );
+        assert_eq!(dirfile.tell("x"), 0);
+        assert_eq!(dirfile.putdata_here("x", &[1u16, 2, 3]).unwrap(), 3);
+        assert_eq!(dirfile.tell("x"), 3);
+        assert_eq!(dirfile.putdata_here("x", &[4u16]).unwrap(), 1);
+        dirfile.seek("x", 0, 1).unwrap();
+        assert_eq!(dirfile.getdata_here::<u16>("x", 1, 0).unwrap(), [2, 3]);
+        //a read past the end only moves on by what was read
+        assert_eq!(dirfile.getdata_here::<u16>("x", 1, 0).unwrap(), [4]);
+        assert_eq!(dirfile.tell("x"), 4);
+        //each field has its own position, counted in its own samples
+        assert_eq!(dirfile.tell("y"), 0);
+        dirfile.seek("x.r", 1, 1).unwrap();
+        assert_eq!(dirfile.tell("x.r"), 3);
+        assert_eq!(dirfile.tell("x"), 4);
+        assert!(dirfile.seek("c", 0, 0).is_err());
+        assert!(dirfile.seek("nothing", 0, 0).is_err());
+        std::fs::remove_dir_all(&dirfile.root_dir).unwrap();
+    }
+}
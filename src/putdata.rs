@@ -1,82 +1,398 @@
-use std::io::{Seek, Write};
+use num::Complex;
 
-use num::cast::AsPrimitive;
+use crate::DataType;
+
+impl crate::EntryLincom {
+    /// what the input of a LINCOM of one input has to be for it to read back as `data`. With
+    /// `round` the input is rounded to the nearest whole number, for inputs that store integers
+    pub(crate) fn inverse<T>(
+        &self,
+        name: &str,
+        data: &[T],
+        round: bool,
+    ) -> crate::Result<Vec<Complex<f64>>>
+    where
+        T: DataType,
+    {
+        let term = &self.terms[0];
+        if term.m == Complex::new(0.0, 0.0) {
+            return Err(format!("{} has a scale of zero and cannot be inverted", name).into());
+        }
+        Ok(data
+            .iter()
+            .map(|value| {
+                let input = (value.to_complex() - term.b) / term.m;
+                match round {
+                    true => Complex::new(input.re.round(), input.im.round()),
+                    false => input,
+                }
+            })
+            .collect())
+    }
+}
 
 impl crate::Dirfile {
-    pub fn putdata<T>(&self, name: &str, first_frame: usize, first_sample: usize, data: Vec<T>)
+    /// Write `data` to a field starting at the given frame and sample and return how many
+    /// samples were written. Any gap between the end of the file and the new data is filled with
+    /// NaN, or zero for integer types. BIT fields and LINCOMs of one input are written by
+    /// working out what their input has to be
+    pub fn putdata<T>(
+        &self,
+        name: &str,
+        first_frame: usize,
+        first_sample: usize,
+        data: &[T],
+    ) -> crate::Result<usize>
+    where
+        T: DataType,
+    {
+        self.check_writable()?;
+        let _writing = self.data_lock.write();
+        let written = self.putdata_locked(name, first_frame, first_sample, data)?;
+        self.clear_field_cache();
+        Ok(written)
+    }
+
+    /// putdata for a caller already holding the data lock for writing
+    fn putdata_locked<T>(
+        &self,
+        name: &str,
+        first_frame: usize,
+        first_sample: usize,
+        data: &[T],
+    ) -> crate::Result<usize>
     where
-        T: 'static + Copy,
-        T: AsPrimitive<f64>,
-        T: AsPrimitive<f32>,
-        T: AsPrimitive<u32>,
-        T: AsPrimitive<u64>,
+        T: DataType,
     {
-        fn write_data<T, F>(
-            writer: &mut (impl Write + Seek),
-            data: Vec<T>,
-            offset: usize,
-            buf_size: usize,
-            convert: F,
-        ) where
-            F: Fn(&mut [u8], T),
-        {
-            let mut buf = vec![0; buf_size];
-            writer
-                .seek(std::io::SeekFrom::Start((offset * buf_size) as u64))
-                .unwrap();
-            for value in data {
-                convert(&mut buf, value);
-                writer.write_all(&buf).unwrap();
+        let entry = self
+            .entry(name)
+            .ok_or_else(|| format!("no field named {}", name))?;
+        let raw = match &entry.entry_type {
+            crate::EntryType::Raw(raw) => raw,
+            crate::EntryType::Bit(bit) => return self.putbit(bit, first_frame, first_sample, data),
+            crate::EntryType::Lincom(lincom) if lincom.terms.len() == 1 => {
+                return self.putlincom(name, lincom, first_frame, first_sample, data)
             }
-            writer.flush().unwrap();
+            entry_type => {
+                return Err(format!(
+                    "{} is a {} field and cannot be written",
+                    name,
+                    entry_type.type_name()
+                )
+                .into())
+            }
+        };
+        let dirfile_options = self.fragments[entry.fragment_index].dirfile_options;
+        let offset = raw.spf as usize * first_frame + first_sample;
+        //the file starts at the frame offset
+        let offset = offset
+            .checked_sub(dirfile_options.frame_offset as usize * raw.spf as usize)
+            .ok_or_else(|| format!("cannot write {} before its frame offset", name))?;
+        let data_type = &raw.data_type;
+        let size = data_type.size();
+        let mut raw_files = self.raw_files.lock().unwrap();
+        let raw_file = raw_files.get(&entry.name, &entry.dirfile_path, true)?;
+        let samples = raw_file.len()? as usize / size;
+        let gap = offset.saturating_sub(samples);
+        let mut buf = data_type.padding(dirfile_options.endian).repeat(gap);
+        data_type.encode_all(dirfile_options.endian, data, &mut buf);
+        raw_file.write_at(((offset - gap) * size) as u64, &buf)?;
+        Ok(data.len())
+    }
+
+    /// write the bits of `data` into the parent of a BIT field, leaving the other bits alone.
+    /// The caller holds the data lock so no other write lands between the read and the write
+    fn putbit<T>(
+        &self,
+        entry_bit: &crate::EntryBit,
+        first_frame: usize,
+        first_sample: usize,
+        data: &[T],
+    ) -> crate::Result<usize>
+    where
+        T: DataType,
+    {
+        let parent = &entry_bit.parent_field;
+        //make sure the parent exists so it can be read
+        self.putdata_locked::<u64>(parent, first_frame, first_sample, &[])?;
+        let mut old = vec![0; data.len()];
+        let read = self.evaluate(parent, first_frame, first_sample, &mut old, None)?;
+        old.truncate(read);
+        let mask = entry_bit.mask();
+        let new: Vec<u64> = data
+            .iter()
+            .enumerate()
+            .map(|(i, value)| {
+                let old = old.get(i).copied().unwrap_or(0);
+                (old & !mask) | ((value.to_u64() << entry_bit.start_bit) & mask)
+            })
+            .collect();
+        self.putdata_locked(parent, first_frame, first_sample, &new)
+    }
+    /// write the input of a LINCOM of one input so it reads back as `data`
+    fn putlincom<T>(
+        &self,
+        name: &str,
+        entry_lincom: &crate::EntryLincom,
+        first_frame: usize,
+        first_sample: usize,
+        data: &[T],
+    ) -> crate::Result<usize>
+    where
+        T: DataType,
+    {
+        let parent = &entry_lincom.terms[0].parent_field;
+        let inverse = entry_lincom.inverse(name, data, self.writes_integers(parent))?;
+        self.putdata_locked(parent, first_frame, first_sample, &inverse)
+    }
+    /// whether what is written to `name` ends up on disk as an integer, which would cut off the
+    /// fraction of a value instead of rounding it. A LINCOM rounds for itself when it is written
+    pub(crate) fn writes_integers(&self, name: &str) -> bool {
+        match self.entry(name).map(|entry| &entry.entry_type) {
+            Some(crate::EntryType::Raw(raw)) => raw.data_type.is_integer(),
+            Some(crate::EntryType::Bit(_)) => true,
+            _ => false,
+        }
+    }
+
+    /// Write at the position of `name` set by seek, moving it on past what was written
+    #[allow(dead_code)]
+    pub fn putdata_here<T>(&self, name: &str, data: &[T]) -> crate::Result<usize>
+    where
+        T: DataType,
+    {
+        let samples = self.putdata(name, 0, self.tell(name), data)?;
+        self.advance(name, samples);
+        Ok(samples)
+    }
+
+    /// Append whole frames of `data` to the end of a RAW field and return the frame they start
+    /// at. Small appends are held back until there is enough to be worth writing, reading the
+    /// field or flush writes them out. A field that ends part way through a frame is padded to
+    /// the next frame first, so fields appended the same number of frames stay aligned
+    #[allow(dead_code)]
+    pub fn putdata_append<T>(&self, name: &str, data: &[T]) -> crate::Result<usize>
+    where
+        T: DataType,
+    {
+        self.check_writable()?;
+        let entry = self
+            .entry(name)
+            .ok_or_else(|| format!("no field named {}", name))?;
+        let crate::EntryType::Raw(raw) = &entry.entry_type else {
+            return Err(format!("{} is a derived field and cannot be written", name).into());
+        };
+        let spf = raw.spf as usize;
+        if !data.len().is_multiple_of(spf) {
+            return Err(format!(
+                "{} has {} samples per frame, {} samples is not a whole number of frames",
+                name,
+                spf,
+                data.len()
+            )
+            .into());
         }
-        let entry = self.entries.get(name).unwrap();
-        let path = entry.dirfile_path.join(name);
-        let file = std::fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(path)
-            .unwrap();
+        let dirfile_options = self.fragments[entry.fragment_index].dirfile_options;
+        let data_type = &raw.data_type;
+        let size = data_type.size();
+        let _writing = self.data_lock.write();
+        let mut raw_files = self.raw_files.lock().unwrap();
+        let raw_file = raw_files.get(&entry.name, &entry.dirfile_path, true)?;
+        let samples = raw_file.len()? as usize / size;
+        let frames = samples.div_ceil(spf);
+        let mut buf = data_type
+            .padding(dirfile_options.endian)
+            .repeat(frames * spf - samples);
+        data_type.encode_all(dirfile_options.endian, data, &mut buf);
+        raw_file.append(&buf)?;
+        drop(raw_files);
+        self.clear_field_cache();
+        Ok(frames + dirfile_options.frame_offset as usize)
+    }
 
-        let mut writer = std::io::BufWriter::new(file);
-        match &entry.entry_type {
-            crate::EntryType::Raw(raw) => {
-                let offset = raw.spf as usize * first_frame  + first_sample;
-                match raw.data_type {
-                    crate::RawTypes::Float64 => {
-                        write_data(&mut writer, data, offset, 8, |buf, value| {
-                            let value: f64 = value.as_();
-                            match entry.dirfile_options.endian {
-                                crate::Endian::Big => buf.copy_from_slice(&value.to_be_bytes()),
-                                crate::Endian::Little => buf.copy_from_slice(&value.to_le_bytes()),
-                            }
-                        });
-                    }
-                    crate::RawTypes::Uint32 => {
-                        write_data(&mut writer, data, offset, 4, |buf, value| {
-                            let value: u32 = value.as_();
-                            match entry.dirfile_options.endian {
-                                crate::Endian::Big => buf.copy_from_slice(&value.to_be_bytes()),
-                                crate::Endian::Little => buf.copy_from_slice(&value.to_le_bytes()),
-                            }
-                        });
-                    }
-                    crate::RawTypes::Uint64 => {
-                        write_data(&mut writer, data, offset, 8, |buf, value| {
-                            let value: u64 = value.as_();
-                            match entry.dirfile_options.endian {
-                                crate::Endian::Big => buf.copy_from_slice(&value.to_be_bytes()),
-                                crate::Endian::Little => buf.copy_from_slice(&value.to_le_bytes()),
-                            }
-                        });
-                    }
+    /// Change the frame offset of a fragment. With `move_data` the RAW files of the fragment are
+    /// shifted so every sample keeps its frame number, otherwise the data is relabeled.
+    #[allow(dead_code)]
+    pub fn alter_frameoffset(
+        &mut self,
+        fragment_index: usize,
+        frame_offset: u64,
+        move_data: bool,
+    ) -> crate::Result<()> {
+        self.check_writable()?;
+        let fragment = self
+            .fragments
+            .get(fragment_index)
+            .ok_or_else(|| format!("no fragment {}", fragment_index))?;
+        let dirfile_options = fragment.dirfile_options;
+        if move_data && frame_offset != dirfile_options.frame_offset {
+            let _writing = self.data_lock.write();
+            //the files are rewritten under the open handles
+            self.raw_files.lock().unwrap().close_all()?;
+            for (name, entry) in &self.entries {
+                let crate::EntryType::Raw(raw) = &entry.entry_type else {
+                    continue;
+                };
+                if entry.fragment_index != fragment_index {
+                    continue;
                 }
-                writer.flush().unwrap(); // Flush once after the loop
-            }
-            _ => {
-                panic!("can only put raw data into a file - derived fields are read only")
+                let path = entry.dirfile_path.join(name);
+                let Ok(old) = std::fs::read(&path) else {
+                    continue; //nothing written yet
+                };
+                let frame_bytes = raw.spf as usize * raw.data_type.size();
+                let new = if frame_offset > dirfile_options.frame_offset {
+                    //frames before the new offset are dropped
+                    let drop = (frame_offset - dirfile_options.frame_offset) as usize * frame_bytes;
+                    old[drop.min(old.len())..].to_vec()
+                } else {
+                    //frames between the new and old offsets are padded
+                    let pad =
+                        (dirfile_options.frame_offset - frame_offset) as usize * raw.spf as usize;
+                    let mut new = raw.data_type.padding(dirfile_options.endian).repeat(pad);
+                    new.extend(old);
+                    new
+                };
+                std::fs::write(path, new)?;
             }
         }
+        self.fragments[fragment_index].dirfile_options.frame_offset = frame_offset;
+        self.clear_field_cache();
+        self.write_directive(fragment_index, "FRAMEOFFSET", &frame_offset.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn bit_writes() {
+        let dirfile = crate::tests::test_dirfile("bit", "w RAW UINT16 1\nb BIT w 3 2\nc BIT w 0\n");
+        dirfile.putdata("w", 0, 0, &[0xffffu64, 0]).unwrap();
+        dirfile.putdata("b", 0, 0, &[0u64, 3]).unwrap();
+        dirfile.putdata("c", 0, 1, &[1u64]).unwrap();
+        assert_eq!(
+            dirfile.getdata::<u64>("w", 0, 0, 0, 2).unwrap(),
+            [0xffe7, 0b11001]
+        );
+        assert_eq!(dirfile.getdata::<u64>("b", 0, 0, 0, 2).unwrap(), [0, 3]);
+        //values wider than the field are cut to its bits
+        dirfile.putdata("b", 0, 0, &[7u64]).unwrap();
+        assert_eq!(dirfile.getdata::<u64>("w", 0, 0, 0, 1).unwrap(), [0xffff]);
+        std::fs::remove_dir_all(&dirfile.root_dir).unwrap();
+    }
+
+    #[test]
+    fn lincom_writes() {
+        let dirfile = crate::tests::test_dirfile(
+            "lincom",
+            "w RAW FLOAT64 1\nl LINCOM w 2 1\nz LINCOM w 0 1\nll LINCOM 2 w 1 0 w 1 0\n",
+        );
+        dirfile.putdata("l", 0, 0, &[5.0, 9.0]).unwrap();
+        assert_eq!(dirfile.getdata::<f64>("w", 0, 0, 0, 2).unwrap(), [2.0, 4.0]);
+        assert_eq!(dirfile.getdata::<f64>("l", 0, 0, 0, 2).unwrap(), [5.0, 9.0]);
+        assert!(dirfile.putdata("z", 0, 0, &[1.0]).is_err());
+        assert!(dirfile.putdata("ll", 0, 0, &[1.0]).is_err());
+        std::fs::remove_dir_all(&dirfile.root_dir).unwrap();
+    }
+
+    #[test]
+    fn frameoffset_padding() {
+        let mut dirfile =
+            crate::tests::test_dirfile("frameoffset", "/FRAMEOFFSET 2\nf RAW FLOAT64 2\n");
+        assert!(dirfile.putdata("f", 1, 0, &[1.0]).is_err());
+        //the gap between the frame offset and the data is padded
+        dirfile.putdata("f", 3, 0, &[1.0, 2.0]).unwrap();
+        let read = dirfile.getdata::<f64>("f", 0, 0, 4, 0).unwrap();
+        assert!(read[..6].iter().all(|x| x.is_nan()));
+        assert_eq!(read[6..], [1.0, 2.0]);
+        //moving the offset back pads the file so the samples keep their frames
+        dirfile.alter_frameoffset(0, 1, true).unwrap();
+        assert_eq!(
+            std::fs::metadata(dirfile.root_dir.join("f")).unwrap().len(),
+            6 * 8
+        );
+        let read = dirfile.getdata::<f64>("f", 0, 0, 4, 0).unwrap();
+        assert!(read[..6].iter().all(|x| x.is_nan()));
+        assert_eq!(read[6..], [1.0, 2.0]);
+        std::fs::remove_dir_all(&dirfile.root_dir).unwrap();
+    }
+
+    #[test]
+    fn appends() {
+        let dirfile = crate::tests::test_dirfile(
+            "append",
+            "/FRAMEOFFSET 1\na RAW UINT16 2\nb RAW FLOAT32 1\nl LINCOM b 1 0\n",
+        );
+        assert_eq!(dirfile.putdata_append("a", &[1u16, 2]).unwrap(), 1);
+        assert_eq!(dirfile.putdata_append("b", &[1.0]).unwrap(), 1);
+        //a field ending part way through a frame is padded to the next one first
+        dirfile.putdata("a", 2, 0, &[3u16]).unwrap();
+        assert_eq!(dirfile.putdata_append("a", &[4u16, 5]).unwrap(), 3);
+        assert_eq!(dirfile.putdata_append("b", &[2.0]).unwrap(), 2);
+        assert_eq!(dirfile.putdata_append("b", &[3.0]).unwrap(), 3);
+        //appends held back are there to read
+        assert_eq!(
+            dirfile.getdata::<u16>("a", 1, 0, 3, 0).unwrap(),
+            [1, 2, 3, 0, 4, 5]
+        );
+        assert_eq!(
+            dirfile.getdata::<f32>("b", 1, 0, 3, 0).unwrap(),
+            [1.0, 2.0, 3.0]
+        );
+        assert!(dirfile.putdata_append("a", &[6u16]).is_err());
+        assert!(dirfile.putdata_append("l", &[6.0]).is_err());
+        assert!(dirfile.putdata_append("nothing", &[6.0]).is_err());
+        std::fs::remove_dir_all(&dirfile.root_dir).unwrap();
+    }
+
+    #[test]
+    fn written_and_padded() {
+        let dirfile = crate::tests::test_dirfile(
+            "written",
+            "i RAW INT32 1\nf RAW FLOAT32 2\nc CONST UINT8 1\nl LINCOM 2 i 1 0 f 1 0\n",
+        );
+        assert_eq!(dirfile.putdata("i", 0, 2, &[5i32, 6]).unwrap(), 2);
+        assert_eq!(
+            dirfile.getdata::<i32>("i", 0, 0, 0, 4).unwrap(),
+            [0, 0, 5, 6]
+        );
+        assert_eq!(dirfile.putdata("f", 1, 0, &[1.5f32]).unwrap(), 1);
+        let read = dirfile.getdata::<f32>("f", 0, 0, 0, 3).unwrap();
+        assert!(read[..2].iter().all(|x| x.is_nan()));
+        assert_eq!(read[2], 1.5);
+        assert_eq!(dirfile.putdata::<f32>("f", 0, 0, &[]).unwrap(), 0);
+        assert_eq!(
+            dirfile.putdata("c", 0, 0, &[1u8]).unwrap_err().to_string(),
+            "c is a CONST field and cannot be written"
+        );
+        assert_eq!(
+            dirfile.putdata("l", 0, 0, &[1.0]).unwrap_err().to_string(),
+            "l is a LINCOM field and cannot be written"
+        );
+        assert!(dirfile.putdata("nothing", 0, 0, &[1.0]).is_err());
+        //only exclusive opens can write
+        let reader = crate::Dirfile::open_readonly(dirfile.root_dir.clone()).unwrap();
+        assert!(reader.putdata("i", 0, 0, &[1i32]).is_err());
+        std::fs::remove_dir_all(&dirfile.root_dir).unwrap();
+    }
+
+    #[test]
+    fn integer_inverses() {
+        let dirfile = crate::tests::test_dirfile(
+            "integer-inverse",
+            "w RAW INT16 1\nl LINCOM w 0.1 0\nb BIT w 0 4\nlb LINCOM b 0.1 0\nll LINCOM l 3 0\n",
+        );
+        //0.3 / 0.1 is a little under 3
+        dirfile.putdata("l", 0, 0, &[0.3, -0.7, 1.26]).unwrap();
+        assert_eq!(
+            dirfile.getdata::<i16>("w", 0, 0, 0, 3).unwrap(),
+            [3, -7, 13]
+        );
+        dirfile.putdata("lb", 0, 0, &[0.3]).unwrap();
+        assert_eq!(dirfile.getdata::<i16>("b", 0, 0, 0, 1).unwrap(), [3]);
+        //only the LINCOM writing the integer field rounds
+        dirfile.putdata("ll", 0, 0, &[0.9]).unwrap();
+        assert_eq!(dirfile.getdata::<i16>("w", 0, 0, 0, 1).unwrap(), [3]);
+        std::fs::remove_dir_all(&dirfile.root_dir).unwrap();
     }
 }
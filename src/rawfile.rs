@@ -0,0 +1,270 @@
+use std::{
+    fs::File,
+    io::{Seek, Write},
+    os::unix::fs::FileExt,
+    path::Path,
+    sync::Arc,
+};
+
+/// how many RAW files a Dirfile keeps open unless told otherwise
+pub const DEFAULT_OPEN_FILES: usize = 64;
+
+/// how much appended data is held back before it is written out
+const APPEND_BUFFER: usize = 64 * 1024;
+
+/// An open RAW file. It is mapped the first time it is read from
+pub struct RawFile {
+    file: Arc<File>, //shared with reads that do not hold on to the cache
+    writable: bool,
+    mmap: Option<Arc<memmap2::Mmap>>,
+    pending: Vec<u8>, //appended but not written yet
+}
+
+impl RawFile {
+    /// the contents of the file, mapped again if its length changed since the last read. The
+    /// map is shared so it can be read without holding on to the cache
+    pub fn map(&mut self) -> std::io::Result<Arc<memmap2::Mmap>> {
+        self.write_pending()?;
+        let len = self.file.metadata()?.len() as usize;
+        if self.mmap.as_ref().map(|mmap| mmap.len()) != Some(len) {
+            self.mmap = Some(Arc::new(map(&self.file)?));
+        }
+        Ok(self.mmap.clone().unwrap())
+    }
+    /// the open file, for reading it without holding on to the cache
+    pub fn handle(&mut self) -> std::io::Result<Arc<File>> {
+        self.write_pending()?;
+        Ok(self.file.clone())
+    }
+    /// length of the file in bytes, counting appends that have not been written yet
+    pub fn len(&self) -> std::io::Result<u64> {
+        Ok(self.file.metadata()?.len() + self.pending.len() as u64)
+    }
+    /// write `bytes` starting `offset` bytes into the file
+    pub fn write_at(&mut self, offset: u64, bytes: &[u8]) -> std::io::Result<()> {
+        self.write_pending()?;
+        let mut file = &*self.file;
+        file.seek(std::io::SeekFrom::Start(offset))?;
+        file.write_all(bytes)
+    }
+    /// add `bytes` to the end of the file, holding on to them while there are only a few
+    pub fn append(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        self.pending.extend_from_slice(bytes);
+        if self.pending.len() >= APPEND_BUFFER {
+            self.write_pending()?;
+        }
+        Ok(())
+    }
+    /// write out whatever append has held back
+    fn write_pending(&mut self) -> std::io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let mut file = &*self.file;
+        file.seek(std::io::SeekFrom::End(0))?;
+        file.write_all(&self.pending)?;
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+impl Drop for RawFile {
+    fn drop(&mut self) {
+        //nowhere to report an error from here, eviction and flush write appends out before this
+        let _ = self.write_pending();
+    }
+}
+
+/// a new read only map of the whole of `file`
+fn map(file: &File) -> std::io::Result<memmap2::Mmap> {
+    // SAFETY: the map is only ever read. Reading a page the file has been truncated past faults
+    // rather than coming up short like read(), so only opens whose lock keeps writers in other
+    // processes out map files, and writes through the Dirfile that shorten a file hold the data
+    // lock for writing, which waits for every read of a map to finish
+    unsafe { memmap2::Mmap::map(file) }
+}
+
+/// read into `buf` starting `offset` bytes into `file` and return how many bytes were read,
+/// fewer than asked for only at the end of the file
+pub fn read_at(file: &File, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match file.read_at(&mut buf[read..], offset + read as u64) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+/// Least recently used cache of open RAW files, shared by reads and writes
+pub struct RawFiles {
+    files: Vec<(String, RawFile)>, //keyed by field name, least recently used first
+    limit: usize,
+}
+
+impl RawFiles {
+    pub fn new(limit: usize) -> RawFiles {
+        RawFiles {
+            files: Vec::new(),
+            limit: limit.max(1),
+        }
+    }
+    /// the open file of field `name` in `dir`, opening it if it is not already. With `write`
+    /// the file is opened for writing and created if it does not exist
+    pub fn get(&mut self, name: &str, dir: &Path, write: bool) -> std::io::Result<&mut RawFile> {
+        let cached = match self.files.iter().position(|(cached, _)| cached == name) {
+            Some(index) => Some(self.files.remove(index)),
+            None => None,
+        };
+        let (name, raw_file) = match cached {
+            Some((name, raw_file)) if raw_file.writable || !write => (name, raw_file),
+            //not open at all or only open for reading
+            _ => {
+                let file = std::fs::OpenOptions::new()
+                    .read(true)
+                    .write(write)
+                    .create(write)
+                    .truncate(false)
+                    .open(dir.join(name))?;
+                let raw_file = RawFile {
+                    file: Arc::new(file),
+                    writable: write,
+                    mmap: None,
+                    pending: Vec::new(),
+                };
+                (name.to_string(), raw_file)
+            }
+        };
+        self.files.push((name, raw_file));
+        self.evict()?;
+        Ok(&mut self.files.last_mut().unwrap().1)
+    }
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+    /// change how many files are kept open, closing the least recently used ones if needed
+    pub fn set_limit(&mut self, limit: usize) -> std::io::Result<()> {
+        self.limit = limit.max(1);
+        self.evict()
+    }
+    /// close the least recently used files over the limit. Their held back appends are written
+    /// first, a file that cannot be written stays open so nothing is lost
+    fn evict(&mut self) -> std::io::Result<()> {
+        while self.files.len() > self.limit {
+            let (name, raw_file) = &mut self.files[0];
+            //this can happen on any call, so say which field it was
+            raw_file.write_pending().map_err(|e| {
+                std::io::Error::new(e.kind(), format!("writing appends to {}: {}", name, e))
+            })?;
+            self.files.remove(0);
+        }
+        Ok(())
+    }
+    /// push everything written to the open files out to disk
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        for (_, raw_file) in &mut self.files {
+            if raw_file.writable {
+                raw_file.write_pending()?;
+                raw_file.file.sync_data()?;
+            }
+        }
+        Ok(())
+    }
+    /// close every open file, writing out held back appends first
+    pub fn close_all(&mut self) -> std::io::Result<()> {
+        for (_, raw_file) in &mut self.files {
+            raw_file.write_pending()?;
+        }
+        self.files.clear();
+        Ok(())
+    }
+}
+
+impl crate::Dirfile {
+    /// the most RAW files kept open between calls
+    #[allow(dead_code)]
+    pub fn open_file_limit(&self) -> usize {
+        self.raw_files.lock().unwrap().limit()
+    }
+    /// change the most RAW files kept open between calls
+    #[allow(dead_code)]
+    pub fn set_open_file_limit(&self, limit: usize) -> crate::Result<()> {
+        self.raw_files.lock().unwrap().set_limit(limit)?;
+        Ok(())
+    }
+    /// Make sure everything putdata wrote has reached the disk
+    #[allow(dead_code)]
+    pub fn flush(&self) -> crate::Result<()> {
+        self.raw_files.lock().unwrap().flush()?;
+        Ok(())
+    }
+    /// Flush and close every open RAW file, the files are opened again as they are needed
+    #[allow(dead_code)]
+    pub fn close_files(&self) -> crate::Result<()> {
+        self.flush()?;
+        self.raw_files.lock().unwrap().close_all()?;
+        Ok(())
+    }
+    /// Flush and close the dirfile
+    #[allow(dead_code)]
+    pub fn close(self) -> crate::Result<()> {
+        self.close_files()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::test_dirfile;
+
+    #[test]
+    fn open_file_limit() {
+        let dirfile = test_dirfile(
+            "open-files",
+            "a RAW UINT8 1\nb RAW UINT8 1\nc RAW UINT8 1\n",
+        );
+        let len = |name| {
+            std::fs::metadata(dirfile.root_dir.join(name))
+                .unwrap()
+                .len()
+        };
+        dirfile.set_open_file_limit(0).unwrap();
+        assert_eq!(dirfile.open_file_limit(), 1);
+        dirfile.set_open_file_limit(2).unwrap();
+        //held back appends are written when their file is closed to make room
+        dirfile.putdata_append("a", &[1u8]).unwrap();
+        assert_eq!(len("a"), 0);
+        dirfile.putdata("b", 0, 0, &[2u8]).unwrap();
+        dirfile.putdata("c", 0, 0, &[3u8]).unwrap();
+        assert_eq!(len("a"), 1);
+        let open: Vec<String> = (dirfile.raw_files.lock().unwrap().files.iter())
+            .map(|(name, _)| name.clone())
+            .collect();
+        assert_eq!(open, ["b", "c"]);
+        //reading the closed file opens it again, closing the least recently used
+        assert_eq!(dirfile.getdata::<u8>("a", 0, 0, 0, 1).unwrap(), [1]);
+        assert_eq!(dirfile.raw_files.lock().unwrap().files[0].0, "c");
+        std::fs::remove_dir_all(&dirfile.root_dir).unwrap();
+    }
+
+    #[test]
+    fn flush_and_close() {
+        let dirfile = test_dirfile("flush", "a RAW UINT16 1\n");
+        let len = || std::fs::metadata(dirfile.root_dir.join("a")).unwrap().len();
+        dirfile.putdata_append("a", &[1u16, 2]).unwrap();
+        assert_eq!(len(), 0);
+        dirfile.flush().unwrap();
+        assert_eq!(len(), 4);
+        dirfile.putdata_append("a", &[3u16]).unwrap();
+        dirfile.close_files().unwrap();
+        assert_eq!(len(), 6);
+        assert!(dirfile.raw_files.lock().unwrap().files.is_empty());
+        //closed files are opened again when they are needed
+        assert_eq!(dirfile.getdata::<u16>("a", 0, 0, 0, 3).unwrap(), [1, 2, 3]);
+        let root_dir = dirfile.root_dir.clone();
+        dirfile.close().unwrap();
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+}
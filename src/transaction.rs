@@ -0,0 +1,428 @@
+use std::collections::HashMap;
+use std::io::Write;
+
+use crate::DataType;
+
+/// where commit records the writes it is about to make, in the root of the dirfile
+const JOURNAL: &str = ".grabdata.journal";
+const JOURNAL_MAGIC: &[u8] = b"grabdata journal 1\n";
+//in place of a name length, marks a complete journal
+const JOURNAL_END: u32 = u32::MAX;
+
+/// putdata calls held back until commit, which makes all of them or, after a crash, none of
+/// them. Dropping a transaction without committing it throws the writes away. RAW fields, BIT
+/// fields of integer RAW fields and LINCOMs of one input can be written
+pub struct Transaction<'a> {
+    dirfile: &'a crate::Dirfile,
+    writes: Vec<StagedWrite>,
+}
+
+struct StagedWrite {
+    name: String,
+    offset: usize,    //in samples from the start of the file
+    padding: Vec<u8>, //one empty sample, to fill a gap before the data
+    bytes: Vec<u8>,
+    mask: Option<Vec<u8>>, //for BIT fields, the bits of `bytes` that are written
+}
+
+/// one write of a journal, already resolved to bytes in a file
+struct JournalRecord<'a> {
+    name: &'a str,
+    offset: u64,
+    bytes: &'a [u8],
+}
+
+impl Transaction<'_> {
+    /// Stage a write of `data`, see Dirfile::putdata. Nothing is written until commit
+    #[allow(dead_code)]
+    pub fn putdata<T>(
+        &mut self,
+        name: &str,
+        first_frame: usize,
+        first_sample: usize,
+        data: &[T],
+    ) -> crate::Result<usize>
+    where
+        T: DataType,
+    {
+        self.dirfile.check_writable()?;
+        let entry = self
+            .dirfile
+            .entry(name)
+            .ok_or_else(|| format!("no field named {}", name))?;
+        match &entry.entry_type {
+            crate::EntryType::Raw(_) => self.stage(name, first_frame, first_sample, data, None),
+            crate::EntryType::Bit(bit) => {
+                //the other bits are only known at commit, so they are merged in there
+                let parent = self.dirfile.entry(&bit.parent_field);
+                match parent.map(|parent| &parent.entry_type) {
+                    Some(crate::EntryType::Raw(raw)) if raw.data_type.is_integer() => {}
+                    _ => {
+                        return Err(format!(
+                            "{} can only be written in a transaction if {} is an integer RAW field",
+                            name, bit.parent_field
+                        )
+                        .into())
+                    }
+                }
+                let shifted: Vec<u64> = data
+                    .iter()
+                    .map(|value| value.to_u64() << bit.start_bit)
+                    .collect();
+                let mask = Some(bit.mask());
+                self.stage(&bit.parent_field, first_frame, first_sample, &shifted, mask)?;
+                Ok(data.len())
+            }
+            crate::EntryType::Lincom(lincom) if lincom.terms.len() == 1 => {
+                let parent = &lincom.terms[0].parent_field;
+                let round = self.dirfile.writes_integers(parent);
+                let inverse = lincom.inverse(name, data, round)?;
+                self.putdata(parent, first_frame, first_sample, &inverse)?;
+                Ok(data.len())
+            }
+            entry_type => Err(format!(
+                "{} is a {} field and cannot be written",
+                name,
+                entry_type.type_name()
+            )
+            .into()),
+        }
+    }
+
+    /// stage a write to the RAW field `name`, of only the bits in `mask` if there is one
+    fn stage<T>(
+        &mut self,
+        name: &str,
+        first_frame: usize,
+        first_sample: usize,
+        data: &[T],
+        mask: Option<u64>,
+    ) -> crate::Result<usize>
+    where
+        T: DataType,
+    {
+        let entry = self
+            .dirfile
+            .entry(name)
+            .ok_or_else(|| format!("no field named {}", name))?;
+        let crate::EntryType::Raw(raw) = &entry.entry_type else {
+            return Err(format!("{} is not a RAW field", name).into());
+        };
+        let dirfile_options = self.dirfile.fragments[entry.fragment_index].dirfile_options;
+        let endian = dirfile_options.endian;
+        //the file starts at the frame offset
+        let offset = (raw.spf as usize * first_frame + first_sample)
+            .checked_sub(dirfile_options.frame_offset as usize * raw.spf as usize)
+            .ok_or_else(|| format!("cannot write {} before its frame offset", name))?;
+        let data_type = &raw.data_type;
+        let mut bytes = Vec::new();
+        data_type.encode_all(endian, data, &mut bytes);
+        let mask = mask.map(|mask| {
+            let mut bytes = Vec::new();
+            data_type.encode_all(endian, &vec![mask; data.len()], &mut bytes);
+            bytes
+        });
+        self.writes.push(StagedWrite {
+            name: entry.name.clone(),
+            offset,
+            padding: data_type.padding(endian),
+            bytes,
+            mask,
+        });
+        Ok(data.len())
+    }
+
+    /// Make every staged write. They are journaled first, so if we crash part way through the
+    /// next Dirfile::new finishes them. Reads through this Dirfile wait for the commit and
+    /// Shared opens cannot be open during one, ReadOnly opens in other processes can see it
+    /// part way through
+    #[allow(dead_code)]
+    pub fn commit(self) -> crate::Result<()> {
+        let dirfile = self.dirfile;
+        //even with nothing staged the journal would be written
+        dirfile.check_writable()?;
+        let _writing = dirfile.data_lock.write();
+        let mut raw_files = dirfile.raw_files.lock().unwrap();
+        //gaps are padded from the end of each file as it will be by then
+        let mut lengths: HashMap<&str, usize> = HashMap::new();
+        let mut resolved: Vec<(&str, u64, Vec<u8>)> = Vec::new();
+        for write in &self.writes {
+            let entry = dirfile.entry(&write.name).unwrap();
+            let raw_file = raw_files.get(&write.name, &entry.dirfile_path, true)?;
+            let len = match lengths.get(write.name.as_str()) {
+                Some(&len) => len,
+                None => raw_file.len()? as usize,
+            };
+            let size = write.padding.len();
+            let gap = write.offset.saturating_sub(len / size);
+            let mut bytes = write.padding.repeat(gap);
+            match &write.mask {
+                None => bytes.extend_from_slice(&write.bytes),
+                Some(mask) => {
+                    //the other bits as the file will be by then, zero past its end
+                    let start = write.offset * size;
+                    let mut old = vec![0; write.bytes.len()];
+                    overlay(&mut old, start, 0, &raw_file.map()?);
+                    for (name, offset, earlier) in &resolved {
+                        if *name == write.name {
+                            overlay(&mut old, start, *offset as usize, earlier);
+                        }
+                    }
+                    bytes.extend(
+                        old.iter()
+                            .zip(&write.bytes)
+                            .zip(mask)
+                            .map(|((old, new), mask)| (old & !mask) | (new & mask)),
+                    );
+                }
+            }
+            let offset = (write.offset - gap) * size;
+            lengths.insert(&write.name, len.max(offset + bytes.len()));
+            resolved.push((write.name.as_str(), offset as u64, bytes));
+        }
+        let records: Vec<JournalRecord> = resolved
+            .iter()
+            .map(|(name, offset, bytes)| JournalRecord {
+                name,
+                offset: *offset,
+                bytes,
+            })
+            .collect();
+
+        let journal_path = dirfile.root_dir.join(JOURNAL);
+        let mut journal = std::fs::File::create(&journal_path)?;
+        journal.write_all(&encode_journal(&records))?;
+        journal.sync_all()?;
+        crate::sync_dir(&dirfile.root_dir)?;
+
+        dirfile.apply_journal(&mut raw_files, &records)?;
+        std::fs::remove_file(&journal_path)?;
+        crate::sync_dir(&dirfile.root_dir)?;
+        drop(raw_files);
+        dirfile.clear_field_cache();
+        Ok(())
+    }
+}
+
+impl crate::Dirfile {
+    /// Start a set of writes that are made all at once by commit
+    #[allow(dead_code)]
+    pub fn transaction(&self) -> Transaction<'_> {
+        Transaction {
+            dirfile: self,
+            writes: Vec::new(),
+        }
+    }
+
+    /// fail if a transaction is part way through its commit or was interrupted, for opens that
+    /// cannot finish it. Its fields may be half written
+    pub(crate) fn check_journal(&self) -> crate::Result<()> {
+        let journal_path = self.root_dir.join(JOURNAL);
+        if journal_path.exists() {
+            return Err(format!(
+                "{:?} is left by a transaction that is being committed or was interrupted, \
+                 open the dirfile exclusively to finish it",
+                journal_path
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Finish the writes of a transaction that was interrupted while committing. A journal that
+    /// was not completely written belongs to a commit that never started writing and is dropped
+    pub(crate) fn replay_journal(&self) -> crate::Result<()> {
+        let journal_path = self.root_dir.join(JOURNAL);
+        let Ok(journal) = std::fs::read(&journal_path) else {
+            return Ok(());
+        };
+        if let Some(records) = decode_journal(&journal) {
+            for record in &records {
+                match self.entry(record.name).map(|entry| &entry.entry_type) {
+                    Some(crate::EntryType::Raw(_)) => {}
+                    _ => {
+                        return Err(format!(
+                            "{:?} writes to {} which is not a RAW field",
+                            journal_path, record.name
+                        )
+                        .into())
+                    }
+                }
+            }
+            let _writing = self.data_lock.write();
+            let mut raw_files = self.raw_files.lock().unwrap();
+            self.apply_journal(&mut raw_files, &records)?;
+        }
+        std::fs::remove_file(&journal_path)?;
+        crate::sync_dir(&self.root_dir)?;
+        Ok(())
+    }
+
+    fn apply_journal(
+        &self,
+        raw_files: &mut crate::rawfile::RawFiles,
+        records: &[JournalRecord],
+    ) -> crate::Result<()> {
+        for record in records {
+            let entry = self.entry(record.name).unwrap();
+            raw_files
+                .get(record.name, &entry.dirfile_path, true)?
+                .write_at(record.offset, record.bytes)?;
+        }
+        raw_files.flush()?;
+        Ok(())
+    }
+}
+
+/// copy the part of `bytes`, which start `offset` bytes into a file, that falls inside `out`,
+/// which starts `start` bytes into the same file
+fn overlay(out: &mut [u8], start: usize, offset: usize, bytes: &[u8]) {
+    let from = start.max(offset);
+    let to = (start + out.len()).min(offset + bytes.len());
+    if from < to {
+        out[from - start..to - start].copy_from_slice(&bytes[from - offset..to - offset]);
+    }
+}
+
+//the magic line, then for each write the length of the field name, the name, the offset and
+//length of the bytes and the bytes, then JOURNAL_END and the number of writes. Numbers are
+//little endian
+fn encode_journal(records: &[JournalRecord]) -> Vec<u8> {
+    let mut journal = JOURNAL_MAGIC.to_vec();
+    for record in records {
+        journal.extend_from_slice(&(record.name.len() as u32).to_le_bytes());
+        journal.extend_from_slice(record.name.as_bytes());
+        journal.extend_from_slice(&record.offset.to_le_bytes());
+        journal.extend_from_slice(&(record.bytes.len() as u64).to_le_bytes());
+        journal.extend_from_slice(record.bytes);
+    }
+    journal.extend_from_slice(&JOURNAL_END.to_le_bytes());
+    journal.extend_from_slice(&(records.len() as u64).to_le_bytes());
+    journal
+}
+
+/// the writes in a journal, None if it was cut short
+fn decode_journal(journal: &[u8]) -> Option<Vec<JournalRecord<'_>>> {
+    fn take<'a>(input: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+        if input.len() < len {
+            return None;
+        }
+        let (taken, rest) = input.split_at(len);
+        *input = rest;
+        Some(taken)
+    }
+    fn take_u64(input: &mut &[u8]) -> Option<u64> {
+        Some(u64::from_le_bytes(take(input, 8)?.try_into().unwrap()))
+    }
+    let mut input = journal.strip_prefix(JOURNAL_MAGIC)?;
+    let mut records = Vec::new();
+    loop {
+        let name_len = u32::from_le_bytes(take(&mut input, 4)?.try_into().unwrap());
+        if name_len == JOURNAL_END {
+            let count = take_u64(&mut input)?;
+            return (input.is_empty() && count == records.len() as u64).then_some(records);
+        }
+        let name = std::str::from_utf8(take(&mut input, name_len as usize)?).ok()?;
+        let offset = take_u64(&mut input)?;
+        let len = take_u64(&mut input)?;
+        let bytes = take(&mut input, len as usize)?;
+        records.push(JournalRecord {
+            name,
+            offset,
+            bytes,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn journal() -> Vec<u8> {
+        encode_journal(&[
+            JournalRecord {
+                name: "a",
+                offset: 8,
+                bytes: &[1, 2, 3],
+            },
+            JournalRecord {
+                name: "ns.b",
+                offset: 0,
+                bytes: &[],
+            },
+        ])
+    }
+
+    #[test]
+    fn journal_round_trip() {
+        let journal = journal();
+        let records = decode_journal(&journal).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(
+            (records[0].name, records[0].offset, records[0].bytes),
+            ("a", 8, &[1, 2, 3][..])
+        );
+        assert_eq!((records[1].name, records[1].bytes.len()), ("ns.b", 0));
+    }
+
+    #[test]
+    fn truncated_journal() {
+        let journal = journal();
+        for len in 0..journal.len() {
+            assert!(decode_journal(&journal[..len]).is_none(), "cut at {}", len);
+        }
+        assert!(decode_journal(&[&journal[..], &[0]].concat()).is_none());
+    }
+
+    #[test]
+    fn staged_bit_and_lincom() {
+        let dirfile = crate::tests::test_dirfile(
+            "transaction",
+            "w RAW UINT16 1\nb BIT w 3 2\nl LINCOM w 2 1\nt LINCOM w 0.1 0\nf RAW FLOAT64 1\nfb BIT f 0\n",
+        );
+        dirfile.putdata("w", 0, 0, &[0xffffu64, 0, 0]).unwrap();
+        let mut transaction = dirfile.transaction();
+        transaction.putdata("b", 0, 0, &[0u64, 3]).unwrap();
+        transaction.putdata("l", 0, 2, &[9.0]).unwrap();
+        transaction.putdata("t", 0, 3, &[0.3]).unwrap();
+        assert!(transaction.putdata("fb", 0, 0, &[1u64]).is_err());
+        transaction.commit().unwrap();
+        assert_eq!(
+            dirfile.getdata::<u64>("w", 0, 0, 0, 4).unwrap(),
+            [0xffe7, 0b11000, 4, 3]
+        );
+        std::fs::remove_dir_all(&dirfile.root_dir).unwrap();
+    }
+
+    #[test]
+    fn journal_blocks_readers() {
+        let dirfile = crate::tests::test_dirfile("journal", "a RAW UINT8 1\n");
+        let root_dir = dirfile.root_dir.clone();
+        dirfile.putdata("a", 0, 0, &[0u8; 4]).unwrap();
+        drop(dirfile);
+        //a commit that crashed after its journal was written
+        let journal = encode_journal(&[JournalRecord {
+            name: "a",
+            offset: 2,
+            bytes: &[7, 8, 9],
+        }]);
+        std::fs::write(root_dir.join(JOURNAL), journal).unwrap();
+        for mode in [crate::OpenMode::ReadOnly, crate::OpenMode::Shared] {
+            let error = crate::Dirfile::open(root_dir.clone(), mode).err().unwrap();
+            assert!(error.to_string().contains("open the dirfile exclusively"));
+        }
+        assert!(crate::Dirfile::new(root_dir.clone(), crate::OpenFlags::default()).is_err());
+        //opening it exclusively finishes the commit
+        drop(crate::Dirfile::open(root_dir.clone(), crate::OpenMode::Exclusive).unwrap());
+        assert!(!root_dir.join(JOURNAL).exists());
+        let reader = crate::Dirfile::new(root_dir.clone(), crate::OpenFlags::default()).unwrap();
+        assert_eq!(
+            reader.getdata::<u8>("a", 0, 0, 0, 5).unwrap(),
+            [0, 0, 7, 8, 9]
+        );
+        //and the writer cannot start another while it is read
+        assert!(crate::Dirfile::open(root_dir.clone(), crate::OpenMode::Exclusive).is_err());
+        drop(reader);
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+}